@@ -0,0 +1,304 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use anyhow::Result;
+use futures::future::join_all;
+use serde::de::DeserializeOwned;
+use wasm_bindgen::JsValue;
+
+use crate::{
+    browser,
+    engine::{self, Audio, EngineError, ImageHandle, Sound},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssetKind {
+    Image,
+    Json,
+    Sound,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssetEntry {
+    pub name: String,
+    pub kind: AssetKind,
+}
+
+/// AssetManifest 列出需要加载的资源, 同一个清单里的资源会同时加载
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AssetManifest {
+    entries: Vec<AssetEntry>,
+}
+
+impl AssetManifest {
+    pub fn new() -> Self {
+        AssetManifest::default()
+    }
+
+    pub fn image(self, name: &str) -> Self {
+        self.with(name, AssetKind::Image)
+    }
+
+    pub fn json(self, name: &str) -> Self {
+        self.with(name, AssetKind::Json)
+    }
+
+    pub fn sound(self, name: &str) -> Self {
+        self.with(name, AssetKind::Sound)
+    }
+
+    /// with 添加一个资源, 已经在清单里的资源不会重复添加
+    fn with(mut self, name: &str, kind: AssetKind) -> Self {
+        if !self.entries.iter().any(|entry| entry.name == name) {
+            self.entries.push(AssetEntry {
+                name: name.into(),
+                kind,
+            });
+        }
+        self
+    }
+
+    pub fn entries(&self) -> &[AssetEntry] {
+        &self.entries
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssetFailure {
+    pub name: String,
+    pub reason: String,
+}
+
+/// LoadingProgress 记录当前阶段已经加载完成和加载失败的资源, 用来绘制加载画面.
+/// 有些资源要等前面的资源加载完才知道, 所以加载分成几个阶段, 每个阶段的进度单独显示
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LoadingProgress {
+    phase: String,
+    total: usize,
+    loaded: usize,
+    failures: Vec<AssetFailure>,
+}
+
+impl LoadingProgress {
+    /// begin 开始一个新的阶段, 进度从 0 开始计算
+    pub fn begin(&mut self, phase: &str, count: usize) {
+        *self = LoadingProgress {
+            phase: phase.into(),
+            total: count,
+            ..LoadingProgress::default()
+        };
+    }
+
+    pub fn succeed(&mut self) {
+        self.loaded += 1;
+    }
+
+    pub fn fail(&mut self, name: &str, reason: String) {
+        self.failures.push(AssetFailure {
+            name: name.into(),
+            reason,
+        });
+    }
+
+    /// reject 把一个已经加载完的资源改成失败, 比如 JSON 的内容解析不了
+    pub fn reject(&mut self, name: &str, reason: String) {
+        self.loaded = self.loaded.saturating_sub(1);
+        self.fail(name, reason);
+    }
+
+    pub fn phase(&self) -> &str {
+        &self.phase
+    }
+
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    pub fn loaded(&self) -> usize {
+        self.loaded
+    }
+
+    pub fn failures(&self) -> &[AssetFailure] {
+        &self.failures
+    }
+
+    /// fraction 是已经结束(成功或者失败)的资源所占的比例
+    pub fn fraction(&self) -> f32 {
+        if self.total == 0 {
+            return 1.0;
+        }
+        (self.loaded + self.failures.len()) as f32 / self.total as f32
+    }
+}
+
+pub enum Asset {
+    Image(ImageHandle),
+    Json(JsValue),
+    Sound(Sound),
+}
+
+/// Assets 是加载好的资源, 按文件名取出
+#[derive(Default)]
+pub struct Assets {
+    assets: HashMap<String, Asset>,
+}
+
+impl Assets {
+    pub fn image(&self, name: &str) -> Result<ImageHandle> {
+        match self.assets.get(name) {
+            Some(Asset::Image(image)) => Ok(image.clone()),
+            _ => Err(EngineError::Asset(format!("Image {} is not loaded", name)).into()),
+        }
+    }
+
+    fn json_value(&self, name: &str) -> Result<&JsValue> {
+        match self.assets.get(name) {
+            Some(Asset::Json(json)) => Ok(json),
+            _ => Err(EngineError::Asset(format!("JSON {} is not loaded", name)).into()),
+        }
+    }
+
+    pub fn sound(&self, name: &str) -> Result<Sound> {
+        match self.assets.get(name) {
+            Some(Asset::Sound(sound)) => Ok(sound.clone()),
+            _ => Err(EngineError::Asset(format!("Sound {} is not loaded", name)).into()),
+        }
+    }
+}
+
+/// AssetLoader 按清单同时加载资源, 每加载完(或失败)一个资源就调用一次 on_progress.
+/// 声音用 GameLoop 的 Audio 解码, 游戏也应该用 audio() 播放, 而不是自己创建 AudioContext
+pub struct AssetLoader {
+    audio: Audio,
+    progress: RefCell<LoadingProgress>,
+    on_progress: Box<dyn Fn(&LoadingProgress)>,
+}
+
+impl AssetLoader {
+    pub fn new(audio: Audio, on_progress: impl Fn(&LoadingProgress) + 'static) -> Self {
+        AssetLoader {
+            audio,
+            progress: RefCell::new(LoadingProgress::default()),
+            on_progress: Box::new(on_progress),
+        }
+    }
+
+    pub fn audio(&self) -> &Audio {
+        &self.audio
+    }
+
+    pub fn progress(&self) -> LoadingProgress {
+        self.progress.borrow().clone()
+    }
+
+    /// load 作为名为 phase 的一个阶段加载清单里的资源, 等所有的资源都结束之后才返回,
+    /// 有资源加载失败时返回的错误里列出所有失败的资源
+    pub async fn load(&self, phase: &str, manifest: &AssetManifest) -> Result<Assets> {
+        self.update(|progress| progress.begin(phase, manifest.entries().len()));
+        let results = join_all(manifest.entries().iter().map(|entry| async move {
+            let result = load_asset(entry, &self.audio).await;
+            self.update(|progress| match &result {
+                Ok(_) => progress.succeed(),
+                Err(err) => progress.fail(&entry.name, err.to_string()),
+            });
+            result.map(|asset| (entry.name.clone(), asset))
+        }))
+        .await;
+
+        let mut assets = Assets::default();
+        let mut failures = Vec::new();
+        for result in results {
+            match result {
+                Ok((name, asset)) => {
+                    assets.assets.insert(name, asset);
+                }
+                Err(err) => failures.push(err.to_string()),
+            }
+        }
+        if failures.is_empty() {
+            Ok(assets)
+        } else {
+            Err(EngineError::Asset(failures.join(", ")).into())
+        }
+    }
+
+    /// json 解析加载好的 JSON, 解析失败的文件和加载失败的一样显示在加载画面上
+    pub fn json<T: DeserializeOwned>(&self, assets: &Assets, name: &str) -> Result<T> {
+        serde_wasm_bindgen::from_value(assets.json_value(name)?.clone()).map_err(|err| {
+            let reason = err.to_string();
+            self.update(|progress| progress.reject(name, reason.clone()));
+            EngineError::Asset(format!("Could not parse {}: {}", name, reason)).into()
+        })
+    }
+
+    fn update(&self, change: impl FnOnce(&mut LoadingProgress)) {
+        change(&mut self.progress.borrow_mut());
+        (self.on_progress)(&self.progress.borrow());
+    }
+}
+
+async fn load_asset(entry: &AssetEntry, audio: &Audio) -> Result<Asset> {
+    match entry.kind {
+        AssetKind::Image => engine::load_image(&entry.name).await.map(Asset::Image),
+        AssetKind::Json => browser::fetch_json(&entry.name).await.map(Asset::Json),
+        AssetKind::Sound => audio.load_sound(&entry.name).await.map(Asset::Sound),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest_skips_duplicate_assets() {
+        let manifest = AssetManifest::new()
+            .json("rhb.json")
+            .sound("background_song.mp3")
+            .sound("background_song.mp3");
+
+        assert_eq!(
+            manifest.entries(),
+            &[
+                AssetEntry {
+                    name: "rhb.json".into(),
+                    kind: AssetKind::Json,
+                },
+                AssetEntry {
+                    name: "background_song.mp3".into(),
+                    kind: AssetKind::Sound,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn progress_counts_failures_as_finished() {
+        let mut progress = LoadingProgress::default();
+        progress.begin("Loading", 4);
+        progress.succeed();
+        progress.fail("tiles.png", "404".into());
+
+        assert_eq!(progress.fraction(), 0.5);
+        assert_eq!(progress.failures()[0].name, "tiles.png");
+
+        progress.succeed();
+        progress.reject("rhb.json", "missing field `frames`".into());
+
+        assert_eq!(progress.loaded(), 1);
+        assert_eq!(progress.fraction(), 0.75);
+        assert_eq!(progress.failures()[1].name, "rhb.json");
+    }
+
+    #[test]
+    fn each_phase_starts_its_own_progress() {
+        let mut progress = LoadingProgress::default();
+        progress.begin("Loading", 2);
+        progress.succeed();
+        progress.succeed();
+        assert_eq!(progress.fraction(), 1.0);
+
+        progress.begin("Loading obstacles", 1);
+
+        assert_eq!(progress.phase(), "Loading obstacles");
+        assert_eq!(progress.fraction(), 0.0);
+    }
+}
@@ -1,5 +1,8 @@
 use anyhow::{anyhow, Result};
-use futures::Future;
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver},
+    Future,
+};
 use js_sys::ArrayBuffer;
 use wasm_bindgen::{
     closure::{WasmClosure, WasmClosureFnOnce},
@@ -12,18 +15,35 @@ use web_sys::{
     Window,
 };
 
+#[cfg(target_arch = "wasm32")]
 macro_rules! log {
     ($($t:tt)*) => {
         web_sys::console::log_1(&format!($($t)*).into())
     };
 }
 
+#[cfg(target_arch = "wasm32")]
 macro_rules! error {
     ( $( $t:tt )* ) => {
         web_sys::console::error_1(&format!( $( $t )* ).into());
     }
 }
 
+// 原生测试里没有 console, 打印到标准输出上
+#[cfg(not(target_arch = "wasm32"))]
+macro_rules! log {
+    ($($t:tt)*) => {
+        println!($($t)*)
+    };
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+macro_rules! error {
+    ( $( $t:tt )* ) => {
+        eprintln!( $( $t )* );
+    }
+}
+
 pub fn window() -> Result<Window> {
     web_sys::window().ok_or_else(|| anyhow!("No window found"))
 }
@@ -131,6 +151,146 @@ pub fn now() -> Result<f64> {
         .now())
 }
 
+pub fn local_storage() -> Result<web_sys::Storage> {
+    window()?
+        .local_storage()
+        .map_err(|err| anyhow!("Could not access localStorage {:#?}", err))?
+        .ok_or_else(|| anyhow!("No localStorage found"))
+}
+
+// 浏览器只允许在这些事件里开始播放声音
+const USER_GESTURE_EVENTS: [&str; 4] = ["keydown", "mousedown", "pointerup", "touchend"];
+
+/// on_user_gesture 在每次按键, 点击或触摸时调用 callback, 返回的 EventListeners 被释放时停止监听
+pub fn on_user_gesture(callback: Box<dyn FnMut()>) -> Result<EventListeners> {
+    let listeners = EventListeners {
+        target: document()?.into(),
+        events: &USER_GESTURE_EVENTS,
+        callback: closure_wrap(callback),
+    };
+    for event in listeners.events {
+        listeners
+            .target
+            .add_event_listener_with_callback(event, listeners.callback.as_ref().unchecked_ref())
+            .map_err(|err| anyhow!("Could not listen to {} {:#?}", event, err))?;
+    }
+    Ok(listeners)
+}
+
+/// EventListeners 是用同一个 callback 监听的一组事件, 被释放时会取消监听
+pub struct EventListeners {
+    target: web_sys::EventTarget,
+    events: &'static [&'static str],
+    callback: Closure<dyn FnMut()>,
+}
+
+impl Drop for EventListeners {
+    fn drop(&mut self) {
+        for event in self.events {
+            if let Err(err) = self
+                .target
+                .remove_event_listener_with_callback(event, self.callback.as_ref().unchecked_ref())
+            {
+                error!("Could not stop listening to {} {:#?}", event, err);
+            }
+        }
+    }
+}
+
+/// gamepads 返回当前连接的手柄, navigator.getGamepads() 里空的位置会被跳过
+pub fn gamepads() -> Result<Vec<web_sys::Gamepad>> {
+    let gamepads = window()?
+        .navigator()
+        .get_gamepads()
+        .map_err(|err| anyhow!("Could not get gamepads {:#?}", err))?;
+    Ok(gamepads
+        .iter()
+        .filter_map(|gamepad| gamepad.dyn_into::<web_sys::Gamepad>().ok())
+        .filter(|gamepad| gamepad.connected())
+        .collect())
+}
+
+const REVOKE_OBJECT_URL_DELAY_MS: i32 = 1000;
+
+/// download_text 把 contents 保存成名为 filename 的文件
+pub fn download_text(filename: &str, contents: &str) -> Result<()> {
+    let parts = js_sys::Array::of1(&JsValue::from_str(contents));
+    let mut options = web_sys::BlobPropertyBag::new();
+    options.type_("application/json");
+    let blob = web_sys::Blob::new_with_str_sequence_and_options(&parts, &options)
+        .map_err(|err| anyhow!("Could not create blob {:#?}", err))?;
+    let url = web_sys::Url::create_object_url_with_blob(&blob)
+        .map_err(|err| anyhow!("Could not create object url {:#?}", err))?;
+    let anchor = document()?
+        .create_element("a")
+        .map_err(|err| anyhow!("Could not create anchor {:#?}", err))?
+        .dyn_into::<web_sys::HtmlAnchorElement>()
+        .map_err(|element| anyhow!("Error converting {:#?} to HtmlAnchorElement", element))?;
+    anchor.set_href(&url);
+    anchor.set_download(filename);
+    anchor.click();
+    // 马上释放 url 会让有些浏览器取消下载, 等下载开始之后再释放
+    let revoke = closure_once(move || {
+        if let Err(err) = web_sys::Url::revoke_object_url(&url) {
+            error!("Could not revoke object url {:#?}", err);
+        }
+    });
+    window()?
+        .set_timeout_with_callback_and_timeout_and_arguments_0(
+            revoke.as_ref().unchecked_ref(),
+            REVOKE_OBJECT_URL_DELAY_MS,
+        )
+        .map_err(|err| anyhow!("Could not schedule revoking object url {:#?}", err))?;
+    revoke.forget();
+    Ok(())
+}
+
+/// pick_text_file 打开文件选择框, 选中的文件读成字符串之后发送到返回的 receiver
+pub fn pick_text_file(accept: &str) -> Result<UnboundedReceiver<String>> {
+    let input = document()?
+        .create_element("input")
+        .map_err(|err| anyhow!("Could not create input {:#?}", err))?
+        .dyn_into::<web_sys::HtmlInputElement>()
+        .map_err(|element| anyhow!("Error converting {:#?} to HtmlInputElement", element))?;
+    input.set_type("file");
+    input.set_accept(accept);
+
+    let (sender, receiver) = unbounded();
+    let picked = input.clone();
+    let on_change = closure_once(move || {
+        let Some(file) = picked.files().and_then(|files| files.get(0)) else {
+            return;
+        };
+        spawn_local(async move {
+            match JsFuture::from(file.text()).await {
+                Ok(text) => {
+                    if let Some(text) = text.as_string() {
+                        let _ = sender.unbounded_send(text);
+                    }
+                }
+                Err(err) => {
+                    error!("Could not read {} {:#?}", file.name(), err);
+                }
+            }
+        });
+    });
+    input.set_onchange(Some(on_change.as_ref().unchecked_ref()));
+    on_change.forget();
+    input.click();
+    Ok(receiver)
+}
+
+/// query_param 读取当前页面 URL 上的查询参数, 比如 `?seed=42` 中的 `seed`
+pub fn query_param(name: &str) -> Result<Option<String>> {
+    let search = window()?
+        .location()
+        .search()
+        .map_err(|err| anyhow!("Could not read location search {:#?}", err))?;
+    let params = web_sys::UrlSearchParams::new_with_str(&search)
+        .map_err(|err| anyhow!("Could not parse query string {:#?}", err))?;
+    Ok(params.get(name))
+}
+
 pub fn draw_ui(html: &str) -> Result<()> {
     find_ui()?
         .insert_adjacent_html("afterbegin", html)
@@ -152,6 +312,12 @@ pub fn hide_ui() -> Result<()> {
         })
 }
 
+/// clear_ui 清掉 #ui 里所有的界面, hide_ui 只会移除第一个
+pub fn clear_ui() -> Result<()> {
+    find_ui()?.set_inner_html("");
+    Ok(())
+}
+
 fn find_ui() -> Result<Element> {
     document().and_then(|doc| {
         doc.get_element_by_id("ui")
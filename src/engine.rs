@@ -1,18 +1,28 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc, sync::Mutex};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+    rc::Rc,
+    sync::Mutex,
+};
 
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
-use futures::channel::{
-    mpsc::{unbounded, UnboundedReceiver},
-    oneshot::channel,
+use futures::{
+    channel::{
+        mpsc::{unbounded, UnboundedReceiver},
+        oneshot::channel,
+    },
+    StreamExt,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use wasm_bindgen::{prelude::Closure, JsCast, JsValue};
 use web_sys::{AudioBuffer, AudioContext, CanvasRenderingContext2d, HtmlElement, HtmlImageElement};
 
 use crate::{
+    assets::{AssetLoader, LoadingProgress},
     browser::{self, LoopClosure},
-    sound,
+    replay::{Replay, ReplayPlayer, ReplayRecorder},
+    sound::{self, Channel, Mixer, PendingSounds, Playback, SoundHandle, VolumeSettings, LOOPPING},
 };
 
 #[derive(Deserialize, Clone)]
@@ -20,7 +30,7 @@ pub struct Sheet {
     pub frames: HashMap<String, Cell>,
 }
 
-#[derive(Deserialize, Clone, Copy)]
+#[derive(Debug, Deserialize, Clone, Copy)]
 pub struct SheetRect {
     pub x: i16,
     pub y: i16,
@@ -28,7 +38,7 @@ pub struct SheetRect {
     pub h: i16,
 }
 
-#[derive(Default, Deserialize, Clone, Copy)]
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub struct Rect {
     pub position: Point, // 水平坐标 x 轴的点, 垂直坐标 y 轴的点
     pub width: i16,      // x = 物体宽度
@@ -87,11 +97,11 @@ pub struct Cell {
 
 pub struct SpriteSheet {
     pub sheet: Sheet,
-    pub image: HtmlImageElement,
+    pub image: ImageHandle,
 }
 
 impl SpriteSheet {
-    pub fn new(sheet: Sheet, image: HtmlImageElement) -> Self {
+    pub fn new(sheet: Sheet, image: ImageHandle) -> Self {
         SpriteSheet { sheet, image }
     }
 
@@ -99,26 +109,116 @@ impl SpriteSheet {
         self.sheet.frames.get(name)
     }
 
-    pub fn draw(&self, renderer: &Renderer, source: &Rect, destination: &Rect) {
-        renderer.draw_image(&self.image, source, destination);
+    pub fn draw(&self, renderer: &dyn Renderer, source: &Rect, destination: &Rect) -> Result<()> {
+        renderer.draw_image(&self.image, source, destination)
+    }
+}
+
+/// EngineError 是 GameLoop 停下来的原因, 会显示在错误画面上
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EngineError {
+    Initialize(String),
+    Asset(String),
+    Update(String),
+    Render(String),
+}
+
+impl EngineError {
+    /// from_error 取出 err 里的 EngineError, 没有的话按 stage 归类
+    pub fn from_error(err: anyhow::Error, stage: fn(String) -> EngineError) -> Self {
+        err.downcast::<EngineError>()
+            .unwrap_or_else(|err| stage(format!("{:#}", err)))
+    }
+}
+
+impl std::fmt::Display for EngineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EngineError::Initialize(message) => write!(f, "Could not start the game: {}", message),
+            EngineError::Asset(message) => write!(f, "Could not load assets: {}", message),
+            EngineError::Update(message) => write!(f, "Error while updating: {}", message),
+            EngineError::Render(message) => write!(f, "Error while drawing: {}", message),
+        }
     }
 }
 
+impl std::error::Error for EngineError {}
+
 #[async_trait(?Send)]
 pub trait Game {
-    async fn initialize(&self) -> Result<Box<dyn Game>>;
-    fn update(&mut self, keystate: &KeyState);
-    fn draw(&self, renderer: &Renderer);
+    /// initialize 通过 loader 加载资源, 加载的进度由 GameLoop 画在加载画面上
+    async fn initialize(&self, loader: &AssetLoader) -> Result<Box<dyn Game>>;
+    /// update 和 draw 返回的错误会让 GameLoop 停下来, 显示错误画面
+    fn update(&mut self, keystate: &KeyState) -> Result<()>;
+    /// alpha 是距离下一次固定步长 update 的进度(0.0 ~ 1.0), 可以用来在两次 update 之间平滑绘制
+    fn draw(&self, renderer: &dyn Renderer, alpha: f32) -> Result<()>;
+
+    /// debug_info 返回显示在调试面板上的额外信息
+    fn debug_info(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// is_paused 为 true 时 GameLoop 不再累计时间, 每帧只调用一次 update 来处理输入
+    fn is_paused(&self) -> bool {
+        false
+    }
+
+    /// replay_seed 返回正在进行的这一局的随机种子, 返回 None 时 GameLoop 不录像
+    fn replay_seed(&self) -> Option<u64> {
+        None
+    }
+
+    /// replay_context 返回回放这一局还需要的设置(比如按键绑定), 会和录像一起保存
+    fn replay_context(&self) -> serde_json::Value {
+        serde_json::Value::Null
+    }
+
+    /// start_replay 用录像的种子和设置重新开始一局. 回放期间游戏不应该保存分数之类的数据
+    fn start_replay(&mut self, _seed: u64, _context: &serde_json::Value) -> Result<()> {
+        Ok(())
+    }
+
+    /// stop_replay 在录像放完之后调用, 恢复玩家自己的设置
+    fn stop_replay(&mut self) {}
+
+    /// touch_layout 返回触摸屏上显示的按钮, 在 initialize 之后读取一次
+    fn touch_layout(&self) -> TouchLayout {
+        TouchLayout::default()
+    }
 }
 
-const FRAME_SIZE: f32 = 1.0 / 60.0 * 1000.0; // 60 帧
+pub const FRAME_SIZE: f32 = 1.0 / 60.0 * 1000.0; // 60 帧
+
+// 一帧最多计入的时间, 标签页从后台切回来时不会一次补上成千上万次 update
+const MAX_FRAME_TIME: f32 = 250.0;
+// 一帧最多执行的 update 次数, 防止 update 本身太慢导致越追越多(spiral of death)
+const MAX_UPDATES_PER_FRAME: u32 = 10;
 
 pub struct GameLoop {
     last_frame: f64,
     accumulated_delta: f32,
+    metrics: LoopMetrics,
+    recording: bool,
+    // 回放结束之后, 这一局剩下的部分不完整, 等下一局开始再录
+    waiting_for_new_run: bool,
+    recorder: Option<ReplayRecorder>,
+    last_replay: Option<Replay>,
+    player: Option<ReplayPlayer>,
+    // 出错之后 tick 和 draw 都不再调用游戏
+    failure: Option<EngineError>,
+    debug_overlay: DebugOverlay,
+}
+
+/// LoopMetrics 记录 GameLoop 为了保持流畅而丢弃的时间
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct LoopMetrics {
+    /// 超过 MAX_FRAME_TIME 而被截断的帧数
+    pub clamped_frames: u64,
+    /// 因为截断或者超过 MAX_UPDATES_PER_FRAME 而没有执行的 update 次数
+    pub dropped_updates: u64,
 }
 
-#[derive(Default, Deserialize, Clone, Copy)]
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub struct Point {
     pub x: i16,
     pub y: i16,
@@ -126,36 +226,104 @@ pub struct Point {
 
 type SharedLoopClourse = Rc<RefCell<Option<LoopClosure>>>;
 
+/// InputReceivers 是浏览器输入事件的接收端, 对应的监听在页面的整个生命周期里只注册一次
+struct InputReceivers {
+    keys: UnboundedReceiver<KeyPress>,
+    pointers: UnboundedReceiver<PointerInput>,
+    visibility: UnboundedReceiver<bool>,
+}
+
+impl InputReceivers {
+    fn new() -> Result<Self> {
+        Ok(InputReceivers {
+            keys: prepare_input()?,
+            pointers: prepare_pointer_input()?,
+            visibility: add_visibility_change_handler()?,
+        })
+    }
+
+    fn process(&mut self, keystate: &mut KeyState, touch_controls: &mut TouchControls) {
+        process_input(keystate, &mut self.keys);
+        process_pointer_input(touch_controls, keystate, &mut self.pointers);
+        process_visibility(keystate, &mut self.visibility);
+    }
+}
+
 impl GameLoop {
+    /// start 一直运行下去: 初始化或者运行中出错时显示错误画面, 点 Retry 之后重新初始化.
+    /// 输入的监听和 AudioContext 只在这里创建一次, 每次重试都复用
     pub async fn start(game: impl Game + 'static) -> Result<()> {
-        let mut keyevent_receiver = prepare_input()?;
+        let inputs = Rc::new(RefCell::new(InputReceivers::new()?));
+        let audio = Audio::new()?;
+        loop {
+            let failure = match GameLoop::run(&game, inputs.clone(), audio.clone()).await {
+                Ok(failure) => failure,
+                Err(err) => EngineError::from_error(err, EngineError::Initialize),
+            };
+            error!("Game loop stopped: {}", failure);
+            draw_error_screen(&failure)?.next().await;
+            browser::hide_ui()?;
+        }
+    }
 
-        let mut game = game.initialize().await?;
-        let mut game_loop = GameLoop {
-            last_frame: browser::now()?,
-            accumulated_delta: 0.0,
-        };
-        let renderer = Renderer {
-            ctx: browser::context()?,
-        };
+    /// run 初始化游戏并开始 requestAnimationFrame 循环, 循环因为错误停下来时返回这个错误
+    async fn run(
+        game: &dyn Game,
+        inputs: Rc<RefCell<InputReceivers>>,
+        audio: Audio,
+    ) -> Result<EngineError> {
+        let loading_renderer = CanvasRenderer::new(browser::context()?);
+        let loader = AssetLoader::new(audio, move |progress| {
+            draw_loading_screen(&loading_renderer, progress);
+        });
+        let mut game = game.initialize(&loader).await?;
+        let mut game_loop = GameLoop::new(browser::now()?);
+        let renderer = CanvasRenderer::new(browser::context()?);
+        *game_loop.debug_overlay_mut() = DebugOverlay::new(cfg!(debug_assertions));
+        let mut touch_controls = TouchControls::new(game.touch_layout());
+        let mut gamepad_input = GamepadInput::new();
+        let mut replay_controls = ReplayControls::new();
+        game_loop.enable_recording();
+        let (failure_sender, failure_receiver) = channel::<EngineError>();
+        let mut failure_sender = Some(failure_sender);
 
         let f: SharedLoopClourse = Rc::new(RefCell::new(None));
         let g = f.clone();
         let mut keystate = KeyState::new();
         *g.borrow_mut() = Some(browser::create_ref_closure(move |perf: f64| {
-            process_input(&mut keystate, &mut keyevent_receiver);
+            inputs
+                .borrow_mut()
+                .process(&mut keystate, &mut touch_controls);
+            gamepad_input.poll(&mut keystate);
+            replay_controls.handle_input(&keystate, &mut game_loop, game.as_mut());
             let frame_time = perf - game_loop.last_frame;
-            game_loop.accumulated_delta += frame_time as f32;
 
-            while game_loop.accumulated_delta > FRAME_SIZE {
-                game.update(&keystate);
-                game_loop.accumulated_delta -= FRAME_SIZE;
+            let update_start = browser::now().unwrap_or(perf);
+            let updates = game_loop.tick(game.as_mut(), perf, &mut keystate);
+            let draw_start = browser::now().unwrap_or(perf);
+            game_loop.draw(game.as_ref(), &renderer);
+            if let Some(failure) = game_loop.failure() {
+                // 不再请求下一帧, 由 start 显示错误画面
+                if let Some(sender) = failure_sender.take() {
+                    let _ = sender.send(failure.clone());
+                }
+                return;
             }
-            game_loop.last_frame = perf;
-            game.draw(&renderer);
-
-            if cfg!(debug_assertions) {
-                unsafe { draw_frame_rate(&renderer, frame_time) }
+            touch_controls.draw(&renderer);
+            let draw_end = browser::now().unwrap_or(perf);
+
+            game_loop.debug_overlay_mut().record_frame(FrameStats {
+                frame_time,
+                updates,
+                update_time: draw_start - update_start,
+                draw_time: draw_end - draw_start,
+            });
+            if game_loop.debug_overlay().is_visible() {
+                let mut info = game.debug_info();
+                info.push(game_loop.replay_status());
+                game_loop
+                    .debug_overlay()
+                    .draw(&renderer, game_loop.metrics(), &info);
             }
 
             let _ = browser::request_animation_frame(f.borrow().as_ref().unwrap());
@@ -167,16 +335,227 @@ impl GameLoop {
                 .ok_or_else(|| anyhow!("GameLoop: Loop is None"))?,
         )?;
 
+        let failure = failure_receiver
+            .await
+            .unwrap_or_else(|_canceled| EngineError::Update("Game loop was dropped".into()));
+        // 释放闭包和里面的游戏, 下次重试会重新初始化
+        g.borrow_mut().take();
+        Ok(failure)
+    }
+
+    pub fn new(start_time: f64) -> Self {
+        GameLoop {
+            last_frame: start_time,
+            accumulated_delta: 0.0,
+            metrics: LoopMetrics::default(),
+            recording: false,
+            waiting_for_new_run: false,
+            recorder: None,
+            last_replay: None,
+            player: None,
+            failure: None,
+            debug_overlay: DebugOverlay::new(false),
+        }
+    }
+
+    pub fn debug_overlay(&self) -> &DebugOverlay {
+        &self.debug_overlay
+    }
+
+    pub fn debug_overlay_mut(&mut self) -> &mut DebugOverlay {
+        &mut self.debug_overlay
+    }
+
+    /// enable_recording 之后每一局都会录像, 可以用 replay 取出来
+    pub fn enable_recording(&mut self) {
+        self.recording = true;
+    }
+
+    /// replay 返回正在录的这一局, 没有的话返回上一局的录像
+    pub fn replay(&self) -> Option<Replay> {
+        self.recorder
+            .as_ref()
+            .map(ReplayRecorder::to_replay)
+            .or_else(|| self.last_replay.clone())
+    }
+
+    /// start_replay 用录像的种子和设置重新开始, 之后的 update 使用录像里的按键而不是玩家的输入
+    pub fn start_replay(&mut self, game: &mut dyn Game, replay: Replay) -> Result<()> {
+        game.start_replay(replay.seed, &replay.context)?;
+        self.recorder = None;
+        self.player = Some(ReplayPlayer::new(replay));
         Ok(())
     }
+
+    pub fn is_replaying(&self) -> bool {
+        self.player.is_some()
+    }
+
+    pub fn replay_status(&self) -> String {
+        match (&self.player, &self.recorder) {
+            (Some(player), _) => format!("Replaying seed {}", player.seed()),
+            (None, Some(recorder)) => format!("Recording {} updates", recorder.updates()),
+            (None, None) => "Not recording".to_string(),
+        }
+    }
+
+    /// step 执行一次 update. 回放时使用录像里的 KeyState, 玩家的按键变化被丢弃,
+    /// 录像的最后一次 update 之后马上结束回放
+    fn step(&mut self, game: &mut dyn Game, keystate: &mut KeyState) -> Result<()> {
+        self.debug_overlay.handle_input(keystate);
+        if let Some(player) = self.player.as_mut() {
+            keystate.end_update();
+            let result = match player.next_keystate() {
+                Some(replay_keystate) => {
+                    let result = game.update(replay_keystate);
+                    replay_keystate.end_update();
+                    result
+                }
+                None => Ok(()),
+            };
+            if player.is_finished() {
+                self.player = None;
+                self.waiting_for_new_run = true;
+                game.stop_replay();
+            }
+            return result;
+        }
+
+        self.track_recording(game);
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record(keystate);
+        }
+        let result = game.update(keystate);
+        keystate.end_update();
+        result
+    }
+
+    /// failure 是让 GameLoop 停下来的错误
+    pub fn failure(&self) -> Option<&EngineError> {
+        self.failure.as_ref()
+    }
+
+    fn fail(&mut self, err: anyhow::Error, stage: fn(String) -> EngineError) {
+        self.failure = Some(EngineError::from_error(err, stage));
+    }
+
+    /// draw 按当前的 alpha 绘制游戏, 出错之后什么也不画
+    pub fn draw(&mut self, game: &dyn Game, renderer: &dyn Renderer) {
+        if self.failure.is_some() {
+            return;
+        }
+        if let Err(err) = game.draw(renderer, self.alpha()) {
+            self.fail(err, EngineError::Render);
+        }
+    }
+
+    /// track_recording 在一局开始时开始录像, 在一局结束(replay_seed 变了)时保存录像
+    fn track_recording(&mut self, game: &dyn Game) {
+        if !self.recording {
+            return;
+        }
+        let seed = game.replay_seed();
+        if self.waiting_for_new_run {
+            self.waiting_for_new_run = seed.is_some();
+            return;
+        }
+        if self.recorder.as_ref().map(ReplayRecorder::seed) == seed {
+            return;
+        }
+        if let Some(recorder) = self.recorder.take() {
+            self.last_replay = Some(recorder.to_replay());
+        }
+        self.recorder = seed.map(|seed| ReplayRecorder::new(seed, game.replay_context()));
+    }
+
+    pub fn alpha(&self) -> f32 {
+        (self.accumulated_delta / FRAME_SIZE).clamp(0.0, 1.0)
+    }
+
+    pub fn metrics(&self) -> LoopMetrics {
+        self.metrics
+    }
+
+    /// tick 用外部传入的时间戳和按键状态推进游戏, 按 FRAME_SIZE 的固定步长调用 update,
+    /// 返回这一帧里 update 被调用的次数. 不依赖 requestAnimationFrame, 可以在原生测试里直接驱动
+    pub fn tick(&mut self, game: &mut dyn Game, timestamp: f64, keystate: &mut KeyState) -> u32 {
+        let mut frame_time = (timestamp - self.last_frame) as f32;
+        self.last_frame = timestamp;
+
+        if self.failure.is_some() {
+            return 0;
+        }
+
+        if game.is_paused() {
+            // 暂停期间的时间直接丢弃, 恢复的时候就不会一口气补上很多次 update
+            self.accumulated_delta = 0.0;
+            if let Err(err) = self.step(game, keystate) {
+                self.fail(err, EngineError::Update);
+            }
+            return 1;
+        }
+
+        if frame_time > MAX_FRAME_TIME {
+            self.metrics.clamped_frames += 1;
+            self.metrics.dropped_updates +=
+                ((frame_time - MAX_FRAME_TIME) / FRAME_SIZE).round() as u64;
+            frame_time = MAX_FRAME_TIME;
+        }
+
+        self.accumulated_delta += frame_time;
+        let mut updates = 0;
+        while self.accumulated_delta > FRAME_SIZE && updates < MAX_UPDATES_PER_FRAME {
+            let result = self.step(game, keystate);
+            self.accumulated_delta -= FRAME_SIZE;
+            updates += 1;
+            if let Err(err) = result {
+                self.fail(err, EngineError::Update);
+                return updates;
+            }
+
+            if game.is_paused() {
+                self.accumulated_delta = 0.0;
+            }
+        }
+
+        if self.accumulated_delta > FRAME_SIZE {
+            // 超出预算的部分直接丢弃, 只保留不足一个步长的余数用来插值
+            self.metrics.dropped_updates += (self.accumulated_delta / FRAME_SIZE) as u64;
+            self.accumulated_delta %= FRAME_SIZE;
+        }
+        updates
+    }
+}
+
+pub trait Renderer {
+    fn clear(&self, rect: &Rect);
+    fn draw_image(&self, image: &ImageHandle, frame: &Rect, destination: &Rect) -> Result<()>;
+    fn draw_entire_image(&self, image: &ImageHandle, position: &Point) -> Result<()>;
+    fn draw_text_with_font(&self, text: &str, location: &Point, font: &str) -> Result<()>;
+    fn fill_rect(&self, rect: &Rect, color: &str);
+    fn stroke_rect(&self, rect: &Rect, color: &str);
+    /// set_alpha 设置之后所有绘制的透明度, 画完半透明的东西之后要设回 1.0
+    fn set_alpha(&self, alpha: f32);
+
+    fn draw_text(&self, text: &str, location: &Point) -> Result<()> {
+        self.draw_text_with_font(text, location, DEFAULT_FONT)
+    }
 }
 
-pub struct Renderer {
+pub const DEFAULT_FONT: &str = "16pt serif";
+
+pub struct CanvasRenderer {
     ctx: CanvasRenderingContext2d,
 }
 
-impl Renderer {
-    pub fn clear(&self, rect: &Rect) {
+impl CanvasRenderer {
+    pub fn new(ctx: CanvasRenderingContext2d) -> Self {
+        CanvasRenderer { ctx }
+    }
+}
+
+impl Renderer for CanvasRenderer {
+    fn clear(&self, rect: &Rect) {
         self.ctx.clear_rect(
             rect.x().into(),
             rect.y().into(),
@@ -185,10 +564,10 @@ impl Renderer {
         )
     }
 
-    pub fn draw_image(&self, image: &HtmlImageElement, frame: &Rect, destination: &Rect) {
+    fn draw_image(&self, image: &ImageHandle, frame: &Rect, destination: &Rect) -> Result<()> {
         self.ctx
             .draw_image_with_html_image_element_and_sw_and_sh_and_dx_and_dy_and_dw_and_dh(
-                image,
+                image.element()?,
                 frame.x().into(),
                 frame.y().into(),
                 frame.width.into(),
@@ -198,26 +577,268 @@ impl Renderer {
                 destination.width.into(),
                 destination.height.into(),
             )
-            .expect("Drawing is throwing exceptions! Unrecoverable error.");
+            .map_err(|err| EngineError::Render(format!("Could not draw image {:#?}", err)).into())
     }
 
-    pub fn draw_entire_image(&self, image: &HtmlImageElement, position: &Point) {
+    fn draw_entire_image(&self, image: &ImageHandle, position: &Point) -> Result<()> {
         self.ctx
-            .draw_image_with_html_image_element(image, position.x.into(), position.y.into())
-            .expect("Drawing is throwing exceptions! Unrecoverable error.");
+            .draw_image_with_html_image_element(
+                image.element()?,
+                position.x.into(),
+                position.y.into(),
+            )
+            .map_err(|err| EngineError::Render(format!("Could not draw image {:#?}", err)).into())
     }
 
-    #[allow(dead_code)]
-    pub fn draw_text(&self, text: &str, location: &Point) -> Result<()> {
-        self.ctx.set_font("16pt serif");
+    fn draw_text_with_font(&self, text: &str, location: &Point, font: &str) -> Result<()> {
+        self.ctx.set_font(font);
         self.ctx
             .fill_text(text, location.x.into(), location.y.into())
             .map_err(|err| anyhow!("Error filling text {:#?}", err))?;
         Ok(())
     }
+
+    fn fill_rect(&self, rect: &Rect, color: &str) {
+        self.ctx.save();
+        self.ctx.set_fill_style(&JsValue::from_str(color));
+        self.ctx.fill_rect(
+            rect.x().into(),
+            rect.y().into(),
+            rect.width.into(),
+            rect.height.into(),
+        );
+        self.ctx.restore();
+    }
+
+    fn stroke_rect(&self, rect: &Rect, color: &str) {
+        self.ctx.save();
+        self.ctx.set_stroke_style(&JsValue::from_str(color));
+        self.ctx.stroke_rect(
+            rect.x().into(),
+            rect.y().into(),
+            rect.width.into(),
+            rect.height.into(),
+        );
+        self.ctx.restore();
+    }
+
+    fn set_alpha(&self, alpha: f32) {
+        self.ctx.set_global_alpha(alpha.into());
+    }
+}
+
+/// OffsetRenderer 把所有绘制的位置整体平移 offset 后交给内部的 Renderer,
+/// 用来在两次 update 之间按 alpha 平滑地绘制滚动的场景
+pub struct OffsetRenderer<'a> {
+    renderer: &'a dyn Renderer,
+    offset: Point,
+}
+
+impl<'a> OffsetRenderer<'a> {
+    pub fn new(renderer: &'a dyn Renderer, offset: Point) -> Self {
+        OffsetRenderer { renderer, offset }
+    }
+
+    fn offset_rect(&self, rect: &Rect) -> Rect {
+        Rect::new(self.offset_point(&rect.position), rect.width, rect.height)
+    }
+
+    fn offset_point(&self, point: &Point) -> Point {
+        Point {
+            x: point.x + self.offset.x,
+            y: point.y + self.offset.y,
+        }
+    }
+}
+
+impl<'a> Renderer for OffsetRenderer<'a> {
+    fn clear(&self, rect: &Rect) {
+        self.renderer.clear(&self.offset_rect(rect));
+    }
+
+    fn draw_image(&self, image: &ImageHandle, frame: &Rect, destination: &Rect) -> Result<()> {
+        self.renderer
+            .draw_image(image, frame, &self.offset_rect(destination))
+    }
+
+    fn draw_entire_image(&self, image: &ImageHandle, position: &Point) -> Result<()> {
+        self.renderer
+            .draw_entire_image(image, &self.offset_point(position))
+    }
+
+    fn draw_text_with_font(&self, text: &str, location: &Point, font: &str) -> Result<()> {
+        self.renderer
+            .draw_text_with_font(text, &self.offset_point(location), font)
+    }
+
+    fn fill_rect(&self, rect: &Rect, color: &str) {
+        self.renderer.fill_rect(&self.offset_rect(rect), color);
+    }
+
+    fn stroke_rect(&self, rect: &Rect, color: &str) {
+        self.renderer.stroke_rect(&self.offset_rect(rect), color);
+    }
+
+    fn set_alpha(&self, alpha: f32) {
+        self.renderer.set_alpha(alpha);
+    }
+}
+
+/// DrawCommand 是 RecordingRenderer 记录下来的一次绘制调用
+#[derive(Debug, Clone, PartialEq)]
+pub enum DrawCommand {
+    Clear(Rect),
+    Image {
+        image: String,
+        frame: Rect,
+        destination: Rect,
+    },
+    EntireImage {
+        image: String,
+        position: Point,
+    },
+    Text {
+        text: String,
+        location: Point,
+        font: String,
+    },
+    FillRect {
+        rect: Rect,
+        color: String,
+    },
+    StrokeRect {
+        rect: Rect,
+        color: String,
+    },
+    Alpha(f32),
+}
+
+/// RecordingRenderer 不依赖浏览器, 只把绘制调用按顺序记录下来, 用于在原生环境下对整帧做快照测试
+#[derive(Debug, Default)]
+pub struct RecordingRenderer {
+    commands: RefCell<Vec<DrawCommand>>,
+}
+
+impl RecordingRenderer {
+    pub fn new() -> Self {
+        RecordingRenderer {
+            commands: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn commands(&self) -> Vec<DrawCommand> {
+        self.commands.borrow().clone()
+    }
+
+    /// take_commands 取出目前记录的所有命令, 并清空记录, 方便逐帧断言
+    pub fn take_commands(&self) -> Vec<DrawCommand> {
+        self.commands.take()
+    }
+
+    fn record(&self, command: DrawCommand) {
+        self.commands.borrow_mut().push(command);
+    }
+}
+
+impl Renderer for RecordingRenderer {
+    fn clear(&self, rect: &Rect) {
+        self.record(DrawCommand::Clear(*rect));
+    }
+
+    fn draw_image(&self, image: &ImageHandle, frame: &Rect, destination: &Rect) -> Result<()> {
+        self.record(DrawCommand::Image {
+            image: image.name().into(),
+            frame: *frame,
+            destination: *destination,
+        });
+        Ok(())
+    }
+
+    fn draw_entire_image(&self, image: &ImageHandle, position: &Point) -> Result<()> {
+        self.record(DrawCommand::EntireImage {
+            image: image.name().into(),
+            position: *position,
+        });
+        Ok(())
+    }
+
+    fn draw_text_with_font(&self, text: &str, location: &Point, font: &str) -> Result<()> {
+        self.record(DrawCommand::Text {
+            text: text.into(),
+            location: *location,
+            font: font.into(),
+        });
+        Ok(())
+    }
+
+    fn fill_rect(&self, rect: &Rect, color: &str) {
+        self.record(DrawCommand::FillRect {
+            rect: *rect,
+            color: color.into(),
+        });
+    }
+
+    fn stroke_rect(&self, rect: &Rect, color: &str) {
+        self.record(DrawCommand::StrokeRect {
+            rect: *rect,
+            color: color.into(),
+        });
+    }
+
+    fn set_alpha(&self, alpha: f32) {
+        self.record(DrawCommand::Alpha(alpha));
+    }
+}
+
+/// ImageHandle 是 Renderer 绘制的图片. 浏览器里包着加载好的 HtmlImageElement,
+/// 原生测试里用 headless 创建, 只有名字和大小
+#[derive(Debug, Clone)]
+pub struct ImageHandle {
+    name: Rc<str>,
+    width: i16,
+    height: i16,
+    element: Option<HtmlImageElement>,
+}
+
+impl ImageHandle {
+    pub fn new(name: &str, element: HtmlImageElement) -> Self {
+        ImageHandle {
+            name: name.into(),
+            width: element.width() as i16,
+            height: element.height() as i16,
+            element: Some(element),
+        }
+    }
+
+    pub fn headless(name: &str, width: i16, height: i16) -> Self {
+        ImageHandle {
+            name: name.into(),
+            width,
+            height,
+            element: None,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn width(&self) -> i16 {
+        self.width
+    }
+
+    pub fn height(&self) -> i16 {
+        self.height
+    }
+
+    fn element(&self) -> Result<&HtmlImageElement> {
+        self.element.as_ref().ok_or_else(|| {
+            EngineError::Render(format!("Image {} has no element to draw", self.name)).into()
+        })
+    }
 }
 
-pub async fn load_image(source: &str) -> Result<HtmlImageElement> {
+pub async fn load_image(source: &str) -> Result<ImageHandle> {
     let image = browser::new_image()?;
     let (complete_tx, complete_rx) = channel::<Result<()>>();
     let success_tx = Rc::new(Mutex::new(Some(complete_tx)));
@@ -238,17 +859,17 @@ pub async fn load_image(source: &str) -> Result<HtmlImageElement> {
     image.set_onerror(Some(error_callback.as_ref().unchecked_ref()));
     image.set_src(source);
     complete_rx.await??;
-    Ok(image)
+    Ok(ImageHandle::new(source, image))
 }
 
 pub struct Image {
-    element: HtmlImageElement,
+    element: ImageHandle,
     bounding_box: Rect,
 }
 
 impl Image {
-    pub fn new(element: HtmlImageElement, position: Point) -> Self {
-        let bounding_box = Rect::new(position, element.width() as i16, element.height() as i16);
+    pub fn new(element: ImageHandle, position: Point) -> Self {
+        let bounding_box = Rect::new(position, element.width(), element.height());
         Self {
             element,
             bounding_box,
@@ -259,8 +880,8 @@ impl Image {
         &self.bounding_box
     }
 
-    pub fn draw(&self, renderer: &Renderer) {
-        renderer.draw_entire_image(&self.element, &self.bounding_box.position);
+    pub fn draw(&self, renderer: &dyn Renderer) -> Result<()> {
+        renderer.draw_entire_image(&self.element, &self.bounding_box.position)
     }
 
     pub fn move_horizontally(&mut self, distance: i16) {
@@ -276,9 +897,10 @@ impl Image {
     }
 }
 
+/// KeyPress 里保存的是 KeyboardEvent.code, 触摸等其他输入也转换成同样的虚拟按键
 pub enum KeyPress {
-    KeyUp(web_sys::KeyboardEvent),
-    KeyDown(web_sys::KeyboardEvent),
+    KeyUp(String),
+    KeyDown(String),
 }
 
 pub fn prepare_input() -> Result<UnboundedReceiver<KeyPress>> {
@@ -288,13 +910,13 @@ pub fn prepare_input() -> Result<UnboundedReceiver<KeyPress>> {
     let on_keydown = browser::closure_wrap(Box::new(move |key_code: web_sys::KeyboardEvent| {
         let _ = keydown_sender
             .borrow_mut()
-            .start_send(KeyPress::KeyDown(key_code));
+            .start_send(KeyPress::KeyDown(key_code.code()));
     }) as Box<dyn FnMut(web_sys::KeyboardEvent)>);
 
     let on_keyup = browser::closure_wrap(Box::new(move |key_code: web_sys::KeyboardEvent| {
         let _ = keyup_sender
             .borrow_mut()
-            .start_send(KeyPress::KeyUp(key_code));
+            .start_send(KeyPress::KeyUp(key_code.code()));
     }) as Box<dyn FnMut(web_sys::KeyboardEvent)>);
 
     browser::canvas()?.set_onkeydown(Some(on_keydown.as_ref().unchecked_ref()));
@@ -313,91 +935,1030 @@ pub fn process_input(state: &mut KeyState, keyevent_receiver: &mut UnboundedRece
             Ok(None) => break,
             Err(_) => break,
             Ok(Some(event)) => match event {
-                KeyPress::KeyDown(event) => state.set_pressed(&event.code(), event),
-                KeyPress::KeyUp(event) => state.set_released(&event.code()),
+                KeyPress::KeyDown(code) => state.set_pressed(&code),
+                KeyPress::KeyUp(code) => state.set_released(&code),
             },
         }
     }
 }
 
-#[derive(Debug, Default)]
+/// KeyState 记录按住的键, 以及两次 update 之间刚按下/刚松开的键.
+/// just_pressed 和 just_released 只在下一次 update 里有效, 由 GameLoop 调用 end_update 清掉
+#[derive(Debug, Default, Clone)]
 pub struct KeyState {
-    pressed_keys: HashMap<String, web_sys::KeyboardEvent>,
+    // 值是这个键已经按住了多少次 update
+    pressed_keys: HashMap<String, u32>,
+    just_pressed: HashSet<String>,
+    just_released: HashSet<String>,
+    // 由 tap 按下的键, 值是还剩多少次 update 自动松开
+    scheduled_releases: HashMap<String, u32>,
+    // 从上一次 update 到现在真正生效的按键变化, 录像用它来重建 KeyState
+    events: Vec<KeyEvent>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KeyEvent {
+    pub code: String,
+    pub pressed: bool,
 }
 
 impl KeyState {
     pub fn new() -> Self {
-        KeyState {
-            pressed_keys: HashMap::new(),
-        }
+        KeyState::default()
     }
 
     pub fn is_pressed(&self, code: &str) -> bool {
         self.pressed_keys.contains_key(code)
     }
 
-    pub fn set_pressed(&mut self, code: &str, event: web_sys::KeyboardEvent) {
-        self.pressed_keys.insert(code.into(), event);
+    pub fn just_pressed(&self, code: &str) -> bool {
+        self.just_pressed.contains(code)
     }
 
-    pub fn set_released(&mut self, code: &str) {
-        self.pressed_keys.remove(code);
+    pub fn just_released(&self, code: &str) -> bool {
+        self.just_released.contains(code)
     }
-}
 
-// =============================================================================
-// AUdio
-#[derive(Clone)]
-pub struct Audio {
-    ctx: AudioContext,
-}
+    /// held_updates 返回这个键已经按住了多少次 update, 没有按住时返回 None
+    pub fn held_updates(&self, code: &str) -> Option<u32> {
+        self.pressed_keys.get(code).copied()
+    }
 
-impl Audio {
-    pub fn new() -> Result<Self> {
-        Ok(Audio {
-            ctx: sound::create_audio_context()?,
-        })
+    pub fn pressed_keys(&self) -> impl Iterator<Item = &str> {
+        self.pressed_keys.keys().map(String::as_str)
     }
 
-    pub async fn load_sound(&self, filename: &str) -> Result<Sound> {
-        let array_buffer = browser::fetch_array_buffer(filename).await?;
-        let audio_buffer = sound::decode_audio_data(&self.ctx, &array_buffer).await?;
-        Ok(Sound {
-            buffer: audio_buffer,
-        })
+    pub fn just_pressed_keys(&self) -> impl Iterator<Item = &str> {
+        self.just_pressed.iter().map(String::as_str)
+    }
+
+    pub fn set_pressed(&mut self, code: &str) {
+        // 按住不放时浏览器会重复发送 keydown, 这些不算新的按下
+        if !self.pressed_keys.contains_key(code) {
+            self.pressed_keys.insert(code.into(), 0);
+            self.just_pressed.insert(code.into());
+            self.events.push(KeyEvent {
+                code: code.into(),
+                pressed: true,
+            });
+        }
+    }
+
+    pub fn set_released(&mut self, code: &str) {
+        self.scheduled_releases.remove(code);
+        if self.pressed_keys.remove(code).is_some() {
+            self.just_released.insert(code.into());
+            self.events.push(KeyEvent {
+                code: code.into(),
+                pressed: false,
+            });
+        }
+    }
+
+    pub fn apply(&mut self, event: &KeyEvent) {
+        if event.pressed {
+            self.set_pressed(&event.code);
+        } else {
+            self.set_released(&event.code);
+        }
+    }
+
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
     }
 
-    pub fn play_sound(&self, sound: &Sound) -> Result<()> {
-        sound::play_sound(&self.ctx, &sound.buffer, sound::LOOPPING::NO)
+    /// tap 按下 code 并在 updates 次 update 之后自动松开, 用来把手势转换成按键
+    pub fn tap(&mut self, code: &str, updates: u32) {
+        if self.is_pressed(code) {
+            self.set_released(code);
+        }
+        self.set_pressed(code);
+        self.scheduled_releases.insert(code.into(), updates);
     }
 
-    pub fn play_looping_sound(&self, sound: &Sound) -> Result<()> {
-        sound::play_sound(&self.ctx, &sound.buffer, sound::LOOPPING::YES)
+    /// end_update 在每次 update 之后调用, 清掉这次的按键变化并累计按住的时间
+    pub fn end_update(&mut self) {
+        self.just_pressed.clear();
+        self.just_released.clear();
+        self.events.clear();
+        self.pressed_keys.values_mut().for_each(|held| *held += 1);
+
+        let mut released = Vec::new();
+        self.scheduled_releases.retain(|code, remaining| {
+            *remaining = remaining.saturating_sub(1);
+            if *remaining == 0 {
+                released.push(code.clone());
+            }
+            *remaining > 0
+        });
+        released.iter().for_each(|code| self.set_released(code));
     }
 }
 
-#[derive(Clone)]
-pub struct Sound {
-    pub buffer: AudioBuffer,
+// =============================================================================
+// Touch controls
+// 触摸和鼠标输入转换成下面这些虚拟按键, 和键盘一样写进 KeyState
+pub const TOUCH_TAP: &str = "Touch:Tap";
+pub const TOUCH_SWIPE_DOWN: &str = "Touch:SwipeDown";
+
+// 轻点相当于按住 TAP_UPDATES 次 update, 这样轻点也能跳到接近完整的高度
+const TAP_UPDATES: u32 = 15;
+const SWIPE_UPDATES: u32 = 1;
+const TAP_DISTANCE: f64 = 20.0;
+const SWIPE_DISTANCE: f64 = 50.0;
+const GESTURE_TIME: f64 = 500.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointerPhase {
+    Down,
+    Move,
+    Up,
+    Cancel,
 }
 
-pub fn add_click_handler(elem: HtmlElement) -> UnboundedReceiver<()> {
-    let (mut click_sender, click_receiver) = unbounded();
-    let on_click = browser::closure_wrap(Box::new(move || {
-        let _ = click_sender.start_send(());
-    }) as Box<dyn FnMut()>);
-    elem.set_onclick(Some(on_click.as_ref().unchecked_ref()));
-    on_click.forget();
-    click_receiver
+/// PointerInput 是一次 pointer 事件, x 和 y 已经换算成 canvas 坐标
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PointerInput {
+    pub phase: PointerPhase,
+    pub id: i32,
+    pub x: f64,
+    pub y: f64,
+    pub time: f64,
+    pub is_touch: bool,
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gesture {
+    Tap,
+    SwipeDown,
+}
 
-    #[test]
-    fn two_rects_that_intersect_on_the_left() {
-        let rect1 = Rect {
+impl Gesture {
+    fn code(&self) -> &'static str {
+        match self {
+            Gesture::Tap => TOUCH_TAP,
+            Gesture::SwipeDown => TOUCH_SWIPE_DOWN,
+        }
+    }
+
+    fn hold_updates(&self) -> u32 {
+        match self {
+            Gesture::Tap => TAP_UPDATES,
+            Gesture::SwipeDown => SWIPE_UPDATES,
+        }
+    }
+}
+
+/// GestureRecognizer 分别跟踪每个 pointer, 多个手指同时触摸时互不干扰
+#[derive(Debug, Default)]
+struct GestureRecognizer {
+    // 每个 pointer 按下时的位置和时间
+    starts: HashMap<i32, (f64, f64, f64)>,
+}
+
+impl GestureRecognizer {
+    fn pointer_down(&mut self, input: &PointerInput) {
+        self.starts.insert(input.id, (input.x, input.y, input.time));
+    }
+
+    /// pointer_move 在向下滑动足够远时立即识别为 SwipeDown, 不用等到松开
+    fn pointer_move(&mut self, input: &PointerInput) -> Option<Gesture> {
+        let (start_x, start_y, start_time) = *self.starts.get(&input.id)?;
+        let (dx, dy) = (input.x - start_x, input.y - start_y);
+        if dy >= SWIPE_DISTANCE && dy > dx.abs() && input.time - start_time <= GESTURE_TIME {
+            self.starts.remove(&input.id);
+            Some(Gesture::SwipeDown)
+        } else {
+            None
+        }
+    }
+
+    fn pointer_up(&mut self, input: &PointerInput) -> Option<Gesture> {
+        if let Some(gesture) = self.pointer_move(input) {
+            return Some(gesture);
+        }
+        let (start_x, start_y, start_time) = self.starts.remove(&input.id)?;
+        let distance = (input.x - start_x).hypot(input.y - start_y);
+        (distance <= TAP_DISTANCE && input.time - start_time <= GESTURE_TIME).then(|| Gesture::Tap)
+    }
+
+    fn cancel(&mut self, input: &PointerInput) {
+        self.starts.remove(&input.id);
+    }
+}
+
+/// TouchButton 是屏幕上的一个按钮, 按住时 code 对应的虚拟按键也一直按住
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TouchButton {
+    pub label: &'static str,
+    pub code: &'static str,
+    pub rect: Rect,
+}
+
+/// TouchLayout 由游戏提供: 有哪些按钮, 以及按钮的图片.
+/// frame 和 pressed_frame 是按钮平时和按下时在 image 里的位置, 没有图片时只画边框
+#[derive(Debug, Clone, Default)]
+pub struct TouchLayout {
+    pub buttons: Vec<TouchButton>,
+    pub image: Option<ImageHandle>,
+    pub frame: Rect,
+    pub pressed_frame: Rect,
+    pub font: &'static str,
+}
+
+impl TouchButton {
+    fn contains(&self, x: f64, y: f64) -> bool {
+        x >= f64::from(self.rect.x())
+            && x < f64::from(self.rect.right())
+            && y >= f64::from(self.rect.y())
+            && y < f64::from(self.rect.bottom())
+    }
+}
+
+/// TouchControls 把 pointer 事件转换成虚拟按键: 轻点, 向下滑动, 以及屏幕上的按钮.
+/// 按钮只有在出现过触摸输入之后才显示
+pub struct TouchControls {
+    gestures: GestureRecognizer,
+    held_buttons: HashMap<i32, &'static str>,
+    visible: bool,
+    layout: TouchLayout,
+}
+
+impl TouchControls {
+    pub fn new(layout: TouchLayout) -> Self {
+        TouchControls {
+            gestures: GestureRecognizer::default(),
+            held_buttons: HashMap::new(),
+            visible: false,
+            layout,
+        }
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    pub fn handle_pointer(&mut self, input: PointerInput, keystate: &mut KeyState) {
+        self.visible = self.visible || input.is_touch;
+        let gesture = match input.phase {
+            PointerPhase::Down => {
+                match self.button_at(input.x, input.y) {
+                    Some(button) => {
+                        keystate.set_pressed(button.code);
+                        self.held_buttons.insert(input.id, button.code);
+                    }
+                    None => self.gestures.pointer_down(&input),
+                }
+                None
+            }
+            PointerPhase::Move if !self.held_buttons.contains_key(&input.id) => {
+                self.gestures.pointer_move(&input)
+            }
+            PointerPhase::Move => None,
+            PointerPhase::Up | PointerPhase::Cancel => {
+                if let Some(code) = self.held_buttons.remove(&input.id) {
+                    keystate.set_released(code);
+                    None
+                } else if input.phase == PointerPhase::Up {
+                    self.gestures.pointer_up(&input)
+                } else {
+                    self.gestures.cancel(&input);
+                    None
+                }
+            }
+        };
+        if let Some(gesture) = gesture {
+            keystate.tap(gesture.code(), gesture.hold_updates());
+        }
+    }
+
+    fn button_at(&self, x: f64, y: f64) -> Option<TouchButton> {
+        if !self.visible {
+            return None;
+        }
+        self.layout
+            .buttons
+            .iter()
+            .find(|button| button.contains(x, y))
+            .copied()
+    }
+
+    pub fn draw(&self, renderer: &dyn Renderer) {
+        if !self.visible {
+            return;
+        }
+        self.layout.buttons.iter().for_each(|button| {
+            let pressed = self.held_buttons.values().any(|code| *code == button.code);
+            match &self.layout.image {
+                Some(image) => {
+                    let frame = if pressed {
+                        &self.layout.pressed_frame
+                    } else {
+                        &self.layout.frame
+                    };
+                    if let Err(err) = renderer.draw_image(image, frame, &button.rect) {
+                        error!("Could not draw touch button {:#?}", err);
+                    }
+                }
+                None => renderer.stroke_rect(&button.rect, "black"),
+            }
+            let location = Point {
+                x: button.rect.x() + 25,
+                y: button.rect.y() + 32,
+            };
+            if let Err(err) =
+                renderer.draw_text_with_font(button.label, &location, self.layout.font)
+            {
+                error!("Could not draw touch button {:#?}", err);
+            }
+        });
+    }
+}
+
+pub fn prepare_pointer_input() -> Result<UnboundedReceiver<PointerInput>> {
+    let (sender, receiver) = unbounded();
+    let sender = Rc::new(RefCell::new(sender));
+    let canvas = browser::canvas()?;
+
+    [
+        PointerPhase::Down,
+        PointerPhase::Move,
+        PointerPhase::Up,
+        PointerPhase::Cancel,
+    ]
+    .into_iter()
+    .for_each(|phase| {
+        let sender = Rc::clone(&sender);
+        let element = canvas.clone();
+        let on_pointer = browser::closure_wrap(Box::new(move |event: web_sys::PointerEvent| {
+            // canvas 可能被 CSS 缩放, 需要换算成 canvas 自己的坐标
+            let scale = f64::from(element.width()) / f64::from(element.client_width().max(1));
+            let _ = sender.borrow_mut().start_send(PointerInput {
+                phase,
+                id: event.pointer_id(),
+                x: f64::from(event.offset_x()) * scale,
+                y: f64::from(event.offset_y()) * scale,
+                time: event.time_stamp(),
+                is_touch: event.pointer_type() == "touch",
+            });
+        }) as Box<dyn FnMut(web_sys::PointerEvent)>);
+        let callback = Some(on_pointer.as_ref().unchecked_ref());
+        match phase {
+            PointerPhase::Down => canvas.set_onpointerdown(callback),
+            PointerPhase::Move => canvas.set_onpointermove(callback),
+            PointerPhase::Up => canvas.set_onpointerup(callback),
+            PointerPhase::Cancel => canvas.set_onpointercancel(callback),
+        }
+        on_pointer.forget();
+    });
+
+    Ok(receiver)
+}
+
+pub fn process_pointer_input(
+    controls: &mut TouchControls,
+    state: &mut KeyState,
+    pointer_receiver: &mut UnboundedReceiver<PointerInput>,
+) {
+    while let Ok(Some(input)) = pointer_receiver.try_next() {
+        controls.handle_pointer(input, state);
+    }
+}
+
+// =============================================================================
+// Gamepad
+// 标准布局手柄的按键也转换成虚拟按键写进 KeyState
+pub const GAMEPAD_A: &str = "Gamepad:A";
+pub const GAMEPAD_B: &str = "Gamepad:B";
+pub const GAMEPAD_START: &str = "Gamepad:Start";
+pub const GAMEPAD_DOWN: &str = "Gamepad:Down";
+pub const GAMEPAD_RIGHT: &str = "Gamepad:Right";
+
+// https://w3c.github.io/gamepad/#remapping 里标准布局的按键编号
+const STANDARD_BUTTONS: [(usize, &str); 5] = [
+    (0, GAMEPAD_A),
+    (1, GAMEPAD_B),
+    (9, GAMEPAD_START),
+    (13, GAMEPAD_DOWN),
+    (15, GAMEPAD_RIGHT),
+];
+// 左摇杆推过这个值才算按下方向
+const AXIS_THRESHOLD: f64 = 0.5;
+const LEFT_STICK_X: usize = 0;
+const LEFT_STICK_Y: usize = 1;
+
+/// GamepadSnapshot 是某一帧手柄的状态, 和 web_sys::Gamepad 分开是为了能在原生测试里构造
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GamepadSnapshot {
+    pub index: u32,
+    pub id: String,
+    pub standard: bool,
+    pub buttons: Vec<bool>,
+    pub axes: Vec<f64>,
+}
+
+impl GamepadSnapshot {
+    fn from_gamepad(gamepad: &web_sys::Gamepad) -> Self {
+        GamepadSnapshot {
+            index: gamepad.index(),
+            id: gamepad.id(),
+            standard: gamepad.mapping() == web_sys::GamepadMappingType::Standard,
+            buttons: gamepad
+                .buttons()
+                .iter()
+                .map(|button| {
+                    button
+                        .dyn_into::<web_sys::GamepadButton>()
+                        .map(|button| button.pressed())
+                        .unwrap_or(false)
+                })
+                .collect(),
+            axes: gamepad
+                .axes()
+                .iter()
+                .map(|axis| axis.as_f64().unwrap_or(0.0))
+                .collect(),
+        }
+    }
+
+    fn pressed_codes(&self) -> impl Iterator<Item = &'static str> + '_ {
+        let button = |index: usize| self.buttons.get(index).copied().unwrap_or(false);
+        let axis = |index: usize| self.axes.get(index).copied().unwrap_or(0.0);
+        STANDARD_BUTTONS
+            .iter()
+            .filter(move |(index, _)| button(*index))
+            .map(|(_, code)| *code)
+            .chain((axis(LEFT_STICK_Y) > AXIS_THRESHOLD).then(|| GAMEPAD_DOWN))
+            .chain((axis(LEFT_STICK_X) > AXIS_THRESHOLD).then(|| GAMEPAD_RIGHT))
+    }
+}
+
+/// HotPlug 是两次轮询之间手柄的连接变化
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HotPlug {
+    Connected(String),
+    // 不是标准布局的手柄没有默认映射, 连接之后会被忽略
+    Unsupported(String),
+    Disconnected(String),
+}
+
+/// GamepadInput 每帧轮询一次手柄, 把按键的变化写进 KeyState, 手柄拔掉时松开它按住的键
+#[derive(Debug, Default)]
+pub struct GamepadInput {
+    connected: HashMap<u32, String>,
+    pressed: HashSet<&'static str>,
+}
+
+impl GamepadInput {
+    pub fn new() -> Self {
+        GamepadInput::default()
+    }
+
+    pub fn poll(&mut self, keystate: &mut KeyState) {
+        // 不支持 Gamepad API 的浏览器就当作没有手柄
+        let gamepads = browser::gamepads().unwrap_or_default();
+        let snapshots: Vec<GamepadSnapshot> =
+            gamepads.iter().map(GamepadSnapshot::from_gamepad).collect();
+        self.update(&snapshots, keystate)
+            .iter()
+            .for_each(|event| match event {
+                HotPlug::Connected(id) => log!("Gamepad connected: {}", id),
+                HotPlug::Unsupported(id) => log!("Gamepad {} has no standard mapping", id),
+                HotPlug::Disconnected(id) => log!("Gamepad disconnected: {}", id),
+            });
+    }
+
+    pub fn update(
+        &mut self,
+        gamepads: &[GamepadSnapshot],
+        keystate: &mut KeyState,
+    ) -> Vec<HotPlug> {
+        let events = self.detect_hot_plug(gamepads);
+
+        let pressed: HashSet<&'static str> = gamepads
+            .iter()
+            .filter(|gamepad| gamepad.standard)
+            .flat_map(GamepadSnapshot::pressed_codes)
+            .collect();
+        self.pressed
+            .difference(&pressed)
+            .for_each(|code| keystate.set_released(code));
+        pressed
+            .difference(&self.pressed)
+            .for_each(|code| keystate.set_pressed(code));
+        self.pressed = pressed;
+        events
+    }
+
+    fn detect_hot_plug(&mut self, gamepads: &[GamepadSnapshot]) -> Vec<HotPlug> {
+        let connected = gamepads
+            .iter()
+            .filter(|gamepad| !self.connected.contains_key(&gamepad.index))
+            .map(|gamepad| {
+                if gamepad.standard {
+                    HotPlug::Connected(gamepad.id.clone())
+                } else {
+                    HotPlug::Unsupported(gamepad.id.clone())
+                }
+            });
+        let disconnected = self
+            .connected
+            .iter()
+            .filter(|(index, _)| !gamepads.iter().any(|gamepad| gamepad.index == **index))
+            .map(|(_, id)| HotPlug::Disconnected(id.clone()));
+        let events = connected.chain(disconnected).collect();
+        self.connected = gamepads
+            .iter()
+            .map(|gamepad| (gamepad.index, gamepad.id.clone()))
+            .collect();
+        events
+    }
+}
+
+// =============================================================================
+// AUdio
+/// Audio 的所有声音都经过 Mixer: 一次性的音效走 Sfx, 循环播放的背景音乐走 Music.
+/// 浏览器在用户第一次操作之前不允许播放声音, 这期间循环播放的声音会排队, 音效直接丢弃.
+/// 原生测试里用 headless 创建, 不发出任何声音
+#[derive(Clone)]
+pub struct Audio {
+    output: Option<Rc<AudioOutput>>,
+    settings: Rc<RefCell<VolumeSettings>>,
+    pending: Rc<RefCell<PendingSounds<SoundHandle>>>,
+}
+
+/// AudioOutput 是浏览器里真正发出声音的部分
+struct AudioOutput {
+    ctx: AudioContext,
+    mixer: Mixer,
+    // 解锁之后就释放, 不再监听用户操作
+    gesture_listeners: RefCell<Option<browser::EventListeners>>,
+}
+
+impl Audio {
+    pub fn new() -> Result<Self> {
+        let ctx = sound::create_audio_context()?;
+        let mixer = Mixer::new(&ctx)?;
+        let unlocked = !sound::is_suspended(&ctx);
+        let audio = Audio {
+            output: Some(Rc::new(AudioOutput {
+                ctx,
+                mixer,
+                gesture_listeners: RefCell::new(None),
+            })),
+            settings: Rc::new(RefCell::new(VolumeSettings::default())),
+            pending: Rc::new(RefCell::new(PendingSounds::new(unlocked))),
+        };
+        if let (false, Some(output)) = (unlocked, &audio.output) {
+            let gesture_audio = audio.clone();
+            let listeners = browser::on_user_gesture(Box::new(move || gesture_audio.unlock()))?;
+            output.gesture_listeners.replace(Some(listeners));
+        }
+        Ok(audio)
+    }
+
+    pub fn headless() -> Self {
+        Audio {
+            output: None,
+            settings: Rc::new(RefCell::new(VolumeSettings::default())),
+            pending: Rc::new(RefCell::new(PendingSounds::new(true))),
+        }
+    }
+
+    pub fn is_suspended(&self) -> bool {
+        self.output
+            .as_ref()
+            .map_or(false, |output| sound::is_suspended(&output.ctx))
+    }
+
+    /// unlock 在用户第一次操作时恢复 AudioContext, 开始排队的声音
+    fn unlock(&self) {
+        let Some(output) = &self.output else {
+            return;
+        };
+        if let Err(err) = sound::resume(&output.ctx) {
+            error!("Could not resume audio {:#?}", err);
+            return;
+        }
+        output.gesture_listeners.take();
+        let queued = self.pending.borrow_mut().unlock();
+        queued.iter().for_each(|handle| {
+            if let Err(err) = handle.start() {
+                error!("Could not start queued sound {:#?}", err);
+            }
+        });
+    }
+
+    /// play 返回 None 表示声音被丢弃了: 还没有解锁, 或者没有输出
+    fn play(
+        &self,
+        sound: &Sound,
+        looping: LOOPPING,
+        channel: Channel,
+    ) -> Result<Option<SoundHandle>> {
+        let Some(output) = &self.output else {
+            return Ok(None);
+        };
+        let playback = self.pending.borrow().playback(&looping);
+        if playback == Playback::Drop {
+            return Ok(None);
+        }
+        let handle = sound::create_sound(
+            &output.ctx,
+            &sound.buffer,
+            looping,
+            output.mixer.bus(channel),
+        )?;
+        if playback == Playback::Start {
+            handle.start()?;
+        } else {
+            self.pending.borrow_mut().queue(handle.clone());
+        }
+        Ok(Some(handle))
+    }
+
+    pub async fn load_sound(&self, filename: &str) -> Result<Sound> {
+        let output = self
+            .output
+            .as_ref()
+            .ok_or_else(|| anyhow!("Could not decode {} without audio output", filename))?;
+        let array_buffer = browser::fetch_array_buffer(filename).await?;
+        let audio_buffer = sound::decode_audio_data(&output.ctx, &array_buffer).await?;
+        Ok(Sound {
+            buffer: audio_buffer,
+        })
+    }
+
+    /// play_sound 在 Sfx 上播放一次性的音效, 解锁之前返回 None
+    pub fn play_sound(&self, sound: &Sound) -> Result<Option<SoundHandle>> {
+        self.play(sound, LOOPPING::NO, Channel::Sfx)
+    }
+
+    /// play_looping_sound 在 Music 上循环播放, 解锁之前会排队, 所以浏览器里总是返回 Some
+    pub fn play_looping_sound(&self, sound: &Sound) -> Result<Option<SoundHandle>> {
+        self.play(sound, LOOPPING::YES, Channel::Music)
+    }
+
+    /// play_tone 在 Sfx 上播放一个合成的滑音, 和音效一样在解锁之前直接跳过
+    pub fn play_tone(&self, from_hz: f32, to_hz: f32, seconds: f64) -> Result<()> {
+        let Some(output) = &self.output else {
+            return Ok(());
+        };
+        if !self.pending.borrow().is_unlocked() {
+            return Ok(());
+        }
+        sound::play_tone(
+            &output.ctx,
+            from_hz,
+            to_hz,
+            seconds,
+            output.mixer.bus(Channel::Sfx),
+        )
+    }
+
+    pub fn volume_settings(&self) -> VolumeSettings {
+        *self.settings.borrow()
+    }
+
+    pub fn set_volume_settings(&self, settings: VolumeSettings) {
+        if let Some(output) = &self.output {
+            output.mixer.apply(&settings);
+        }
+        self.settings.replace(settings);
+    }
+}
+
+#[derive(Clone)]
+pub struct Sound {
+    pub buffer: AudioBuffer,
+}
+
+pub fn add_click_handler(elem: HtmlElement) -> UnboundedReceiver<()> {
+    let (mut click_sender, click_receiver) = unbounded();
+    let on_click = browser::closure_wrap(Box::new(move || {
+        let _ = click_sender.start_send(());
+    }) as Box<dyn FnMut()>);
+    elem.set_onclick(Some(on_click.as_ref().unchecked_ref()));
+    on_click.forget();
+    click_receiver
+}
+
+/// add_visibility_change_handler 在页面被隐藏或重新显示时发送 document.hidden 的值
+pub fn add_visibility_change_handler() -> Result<UnboundedReceiver<bool>> {
+    let (mut visibility_sender, visibility_receiver) = unbounded();
+    let document = browser::document()?;
+    let hidden_document = document.clone();
+    let on_visibility_change = browser::closure_wrap(Box::new(move || {
+        let _ = visibility_sender.start_send(hidden_document.hidden());
+    }) as Box<dyn FnMut()>);
+    document.set_onvisibilitychange(Some(on_visibility_change.as_ref().unchecked_ref()));
+    on_visibility_change.forget();
+    Ok(visibility_receiver)
+}
+
+// 页面被隐藏也当作一个虚拟按键, 这样录像可以重现切换标签页时的自动暂停
+pub const PAGE_HIDDEN: &str = "Page:Hidden";
+
+pub fn process_visibility(state: &mut KeyState, visibility_receiver: &mut UnboundedReceiver<bool>) {
+    while let Ok(Some(hidden)) = visibility_receiver.try_next() {
+        if hidden {
+            state.set_pressed(PAGE_HIDDEN);
+        } else {
+            state.set_released(PAGE_HIDDEN);
+        }
+    }
+}
+
+// =============================================================================
+// Replay controls
+pub const DOWNLOAD_REPLAY_KEY: &str = "F8";
+pub const UPLOAD_REPLAY_KEY: &str = "F9";
+
+/// ReplayControls 用 F8 下载当前的录像, 用 F9 选择一个录像文件回放
+#[derive(Default)]
+pub struct ReplayControls {
+    held_keys: HashSet<&'static str>,
+    upload: Option<UnboundedReceiver<String>>,
+}
+
+impl ReplayControls {
+    pub fn new() -> Self {
+        ReplayControls::default()
+    }
+
+    // 这里每帧调用一次而不是每次 update, 所以不能用 KeyState 的 just_pressed
+    fn newly_pressed(&mut self, keystate: &KeyState, code: &'static str) -> bool {
+        let pressed = keystate.is_pressed(code);
+        let was_held = if pressed {
+            !self.held_keys.insert(code)
+        } else {
+            self.held_keys.remove(code)
+        };
+        pressed && !was_held
+    }
+
+    pub fn handle_input(
+        &mut self,
+        keystate: &KeyState,
+        game_loop: &mut GameLoop,
+        game: &mut dyn Game,
+    ) {
+        if self.newly_pressed(keystate, DOWNLOAD_REPLAY_KEY) {
+            if let Err(err) = download_replay(game_loop) {
+                error!("Could not download replay {:#?}", err);
+            }
+        }
+
+        if self.newly_pressed(keystate, UPLOAD_REPLAY_KEY) {
+            match browser::pick_text_file(".json,application/json") {
+                Ok(upload) => self.upload = Some(upload),
+                Err(err) => {
+                    error!("Could not open replay file {:#?}", err);
+                }
+            }
+        }
+
+        let Some(upload) = self.upload.as_mut() else {
+            return;
+        };
+        if let Ok(Some(json)) = upload.try_next() {
+            self.upload = None;
+            match Replay::from_json(&json) {
+                Ok(replay) => {
+                    if let Err(err) = game_loop.start_replay(game, replay) {
+                        error!("Could not start replay {:#?}", err);
+                    }
+                }
+                Err(err) => {
+                    error!("Could not load replay {:#?}", err);
+                }
+            }
+        }
+    }
+}
+
+fn download_replay(game_loop: &GameLoop) -> Result<()> {
+    let replay = game_loop
+        .replay()
+        .ok_or_else(|| anyhow!("No replay has been recorded yet"))?;
+    browser::download_text(
+        &format!("walk_the_dog_{}.json", replay.seed),
+        &replay.to_json()?,
+    )
+}
+
+// =============================================================================
+// Loading screen
+const LOADING_BAR: Rect = Rect::new_from_x_y(100, 280, 400, 30);
+const LOADING_BAR_COLOR: &str = "#2980b9";
+const LOADING_FONT: &str = "24px 'Ken Future'";
+const LOADING_FAILURE_FONT: &str = "14px monospace";
+const LOADING_LINE_HEIGHT: i16 = 18;
+
+/// draw_loading_screen 画出资源加载的进度条, 以及加载失败的资源
+pub fn draw_loading_screen(renderer: &dyn Renderer, progress: &LoadingProgress) {
+    renderer.clear(&Rect::new_from_x_y(0, 0, 600, 600));
+    let finished = progress.loaded() + progress.failures().len();
+    let title = format!("{} {}/{}", progress.phase(), finished, progress.total());
+    let mut lines = vec![(title, LOADING_FONT, LOADING_BAR.y() - 20)];
+    lines.extend(
+        progress
+            .failures()
+            .iter()
+            .enumerate()
+            .map(|(index, failure)| {
+                (
+                    format!("Could not load {}: {}", failure.name, failure.reason),
+                    LOADING_FAILURE_FONT,
+                    LOADING_BAR.bottom() + 30 + index as i16 * LOADING_LINE_HEIGHT,
+                )
+            }),
+    );
+    for (text, font, y) in lines {
+        let location = Point {
+            x: LOADING_BAR.x(),
+            y,
+        };
+        if let Err(err) = renderer.draw_text_with_font(&text, &location, font) {
+            error!("Could not draw loading screen {:#?}", err);
+        }
+    }
+
+    let filled = (f32::from(LOADING_BAR.width) * progress.fraction()).round() as i16;
+    renderer.fill_rect(
+        &Rect::new(LOADING_BAR.position, filled, LOADING_BAR.height),
+        LOADING_BAR_COLOR,
+    );
+    renderer.stroke_rect(&LOADING_BAR, LOADING_BAR_COLOR);
+}
+
+// =============================================================================
+// Error screen
+// 错误画面放在 #ui 里, 这样 canvas 出问题的时候也能显示出来
+fn draw_error_screen(failure: &EngineError) -> Result<UnboundedReceiver<()>> {
+    // 出错时可能还留着结算或者改键的界面, 先全部清掉
+    browser::clear_ui()?;
+    browser::draw_ui(&error_screen_ui(failure))?;
+    browser::find_html_element_by_id("retry").map(add_click_handler)
+}
+
+fn error_screen_ui(failure: &EngineError) -> String {
+    format!(
+        "<div id='error_screen' class='error_screen'><h2>Something went wrong</h2><p>{}</p><button id='retry'>Retry</button></div>",
+        escape_html(&failure.to_string())
+    )
+}
+
+// 错误信息里可能带着文件名或者 JSON 片段, 不能直接当成 HTML 插进去
+fn escape_html(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+// =============================================================================
+// Debug overlay
+pub const DEBUG_OVERLAY_KEY: &str = "F3";
+const FRAME_HISTORY: usize = 120;
+const DEBUG_PANEL: Rect = Rect::new_from_x_y(350, 0, 250, 200);
+const DEBUG_PANEL_COLOR: &str = "rgba(255, 255, 255, 0.75)";
+const DEBUG_FONT: &str = "12px monospace";
+const DEBUG_LINE_HEIGHT: i16 = 14;
+const GRAPH_BOTTOM: i16 = 195;
+const GRAPH_MAX_HEIGHT: i16 = 50;
+const GRAPH_OK_COLOR: &str = "rgba(39, 174, 96, 0.8)";
+const GRAPH_SLOW_COLOR: &str = "rgba(192, 57, 43, 0.8)";
+
+/// FrameStats 是 GameLoop 每一帧交给 DebugOverlay 的统计信息, 时间单位都是毫秒
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct FrameStats {
+    pub frame_time: f64,
+    pub updates: u32,
+    pub update_time: f64,
+    pub draw_time: f64,
+}
+
+/// DebugOverlay 由 GameLoop 持有, 按 F3 在运行时显示或隐藏,
+/// 显示帧率, 每帧的 update 次数和耗时, 以及游戏自己提供的调试信息
+#[derive(Debug)]
+pub struct DebugOverlay {
+    visible: bool,
+    frame_times: VecDeque<f64>,
+    frames_counted: u32,
+    total_frame_time: f64,
+    frame_rate: u32,
+    last_frame: FrameStats,
+}
+
+impl DebugOverlay {
+    pub fn new(visible: bool) -> Self {
+        DebugOverlay {
+            visible,
+            frame_times: VecDeque::with_capacity(FRAME_HISTORY),
+            frames_counted: 0,
+            total_frame_time: 0.0,
+            frame_rate: 0,
+            last_frame: FrameStats::default(),
+        }
+    }
+
+    pub fn is_visible(&self) -> bool {
+        self.visible
+    }
+
+    /// handle_input 由 GameLoop 在每次 update 之前调用, 这样才能用 just_pressed
+    fn handle_input(&mut self, keystate: &KeyState) {
+        if keystate.just_pressed(DEBUG_OVERLAY_KEY) {
+            self.visible = !self.visible;
+        }
+    }
+
+    pub fn record_frame(&mut self, stats: FrameStats) {
+        self.frames_counted += 1;
+        self.total_frame_time += stats.frame_time;
+        if self.total_frame_time > 1000.0 {
+            self.frame_rate = self.frames_counted;
+            self.frames_counted = 0;
+            self.total_frame_time = 0.0;
+        }
+
+        if self.frame_times.len() == FRAME_HISTORY {
+            self.frame_times.pop_front();
+        }
+        self.frame_times.push_back(stats.frame_time);
+        self.last_frame = stats;
+    }
+
+    pub fn draw(&self, renderer: &dyn Renderer, metrics: LoopMetrics, game_info: &[String]) {
+        if !self.visible {
+            return;
+        }
+
+        renderer.fill_rect(&DEBUG_PANEL, DEBUG_PANEL_COLOR);
+        let lines = [
+            format!("FPS {}", self.frame_rate),
+            format!("Updates/frame {}", self.last_frame.updates),
+            format!("Update {:.2}ms", self.last_frame.update_time),
+            format!("Draw {:.2}ms", self.last_frame.draw_time),
+            format!("Dropped updates {}", metrics.dropped_updates),
+        ];
+        lines
+            .iter()
+            .chain(game_info.iter())
+            .enumerate()
+            .for_each(|(index, line)| {
+                let location = Point {
+                    x: DEBUG_PANEL.x() + 5,
+                    y: DEBUG_PANEL.y() + DEBUG_LINE_HEIGHT * (index as i16 + 1),
+                };
+                if let Err(err) = renderer.draw_text_with_font(line, &location, DEBUG_FONT) {
+                    error!("Could not draw debug overlay {:#?}", err);
+                }
+            });
+        self.draw_frame_graph(renderer);
+    }
+
+    /// draw_frame_graph 从右往左画出最近 FRAME_HISTORY 帧的耗时, 超过一个步长的帧标成红色
+    fn draw_frame_graph(&self, renderer: &dyn Renderer) {
+        let bar_width = DEBUG_PANEL.width / FRAME_HISTORY as i16;
+        self.frame_times
+            .iter()
+            .rev()
+            .enumerate()
+            .for_each(|(index, frame_time)| {
+                let height = ((frame_time * 2.0) as i16).clamp(1, GRAPH_MAX_HEIGHT);
+                let color = if *frame_time > f64::from(FRAME_SIZE) * 1.5 {
+                    GRAPH_SLOW_COLOR
+                } else {
+                    GRAPH_OK_COLOR
+                };
+                renderer.fill_rect(
+                    &Rect::new_from_x_y(
+                        DEBUG_PANEL.right() - bar_width * (index as i16 + 1),
+                        GRAPH_BOTTOM - height,
+                        bar_width,
+                        height,
+                    ),
+                    color,
+                );
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn two_rects_that_intersect_on_the_left() {
+        let rect1 = Rect {
             position: Point { x: 10, y: 10 },
             height: 100,
             width: 100,
@@ -411,26 +1972,489 @@ mod tests {
 
         assert!(rect2.intersects(&rect1));
     }
-}
 
-unsafe fn draw_frame_rate(renderer: &Renderer, frame_time: f64) {
-    static mut FRAMES_COUNTED: i32 = 0;
-    static mut TOTAL_FRAME_TIME: f64 = 0.0;
-    static mut FRAME_RATE: i32 = 0;
+    #[test]
+    fn recording_renderer_captures_draw_calls_in_order() {
+        let renderer = RecordingRenderer::new();
+        let background = Image::new(
+            ImageHandle::headless("BG.png", 1000, 600),
+            Point { x: -20, y: 0 },
+        );
+        let sheet = SpriteSheet::new(
+            Sheet {
+                frames: HashMap::new(),
+            },
+            ImageHandle::headless("tiles.png", 512, 512),
+        );
+        renderer.clear(&Rect::new_from_x_y(0, 0, 600, 600));
+        background.draw(&renderer).unwrap();
+        sheet
+            .draw(
+                &renderer,
+                &Rect::new_from_x_y(0, 0, 128, 128),
+                &Rect::new_from_x_y(200, 400, 128, 128),
+            )
+            .unwrap();
+        renderer
+            .draw_text("Frame Rate 60", &Point { x: 400, y: 100 })
+            .unwrap();
+
+        assert_eq!(background.right(), 980);
+        assert_eq!(
+            renderer.take_commands(),
+            vec![
+                DrawCommand::Clear(Rect::new_from_x_y(0, 0, 600, 600)),
+                DrawCommand::EntireImage {
+                    image: "BG.png".into(),
+                    position: Point { x: -20, y: 0 },
+                },
+                DrawCommand::Image {
+                    image: "tiles.png".into(),
+                    frame: Rect::new_from_x_y(0, 0, 128, 128),
+                    destination: Rect::new_from_x_y(200, 400, 128, 128),
+                },
+                DrawCommand::Text {
+                    text: "Frame Rate 60".into(),
+                    location: Point { x: 400, y: 100 },
+                    font: DEFAULT_FONT.into(),
+                },
+            ]
+        );
+        assert!(renderer.commands().is_empty());
+    }
 
-    FRAMES_COUNTED += 1;
-    TOTAL_FRAME_TIME += frame_time;
+    #[derive(Default)]
+    struct CountingGame {
+        updates: u32,
+        jumps: Vec<u32>,
+        paused: bool,
+        seed: Option<u64>,
+        fail_at: Option<u32>,
+    }
 
-    if TOTAL_FRAME_TIME > 1000.0 {
-        FRAME_RATE = FRAMES_COUNTED;
-        TOTAL_FRAME_TIME = 0.0;
-        FRAMES_COUNTED = 0;
+    #[async_trait(?Send)]
+    impl Game for CountingGame {
+        async fn initialize(&self, _loader: &AssetLoader) -> Result<Box<dyn Game>> {
+            Ok(Box::new(CountingGame::default()))
+        }
+
+        fn update(&mut self, keystate: &KeyState) -> Result<()> {
+            self.updates += 1;
+            if keystate.is_pressed("Space") {
+                self.jumps.push(self.updates);
+            }
+            self.paused = keystate.is_pressed("Escape");
+            if self.fail_at == Some(self.updates) {
+                return Err(anyhow!("Update {} failed", self.updates));
+            }
+            Ok(())
+        }
+
+        fn draw(&self, _renderer: &dyn Renderer, _alpha: f32) -> Result<()> {
+            Ok(())
+        }
+
+        fn is_paused(&self) -> bool {
+            self.paused
+        }
+
+        fn replay_seed(&self) -> Option<u64> {
+            self.seed
+        }
+
+        fn start_replay(&mut self, seed: u64, _context: &serde_json::Value) -> Result<()> {
+            *self = CountingGame {
+                seed: Some(seed),
+                ..CountingGame::default()
+            };
+            Ok(())
+        }
     }
 
-    if let Err(err) = renderer.draw_text(
-        &format!("Frame Rate {}", FRAME_RATE),
-        &Point { x: 400, y: 100 },
-    ) {
-        error!("Could not draw text {:#?}", err);
-    };
+    #[test]
+    fn game_loop_runs_fixed_steps_for_injected_timestamps() {
+        let mut game = CountingGame::default();
+        let mut game_loop = GameLoop::new(0.0);
+        let mut keystate = KeyState::new();
+
+        assert_eq!(game_loop.tick(&mut game, 10.0, &mut keystate), 0);
+        assert_eq!(game_loop.tick(&mut game, 20.0, &mut keystate), 1);
+        // 一次性过去 100ms, 需要补上积压的更新
+        assert_eq!(game_loop.tick(&mut game, 120.0, &mut keystate), 6);
+        assert_eq!(game.updates, 7);
+    }
+
+    #[test]
+    fn game_loop_passes_injected_keystate_to_update() {
+        let mut game = CountingGame::default();
+        let mut game_loop = GameLoop::new(0.0);
+        let mut keystate = KeyState::new();
+
+        let mut timestamp = 0.0;
+        for frame in 1..=3 {
+            if frame == 2 {
+                keystate.set_pressed("Space");
+            } else {
+                keystate.set_released("Space");
+            }
+            timestamp += 17.0;
+            game_loop.tick(&mut game, timestamp, &mut keystate);
+        }
+
+        assert_eq!(game.updates, 3);
+        assert_eq!(game.jumps, vec![2]);
+    }
+
+    #[test]
+    fn game_loop_clears_key_edges_after_each_update() {
+        let mut game = CountingGame::default();
+        let mut game_loop = GameLoop::new(0.0);
+        let mut keystate = KeyState::new();
+
+        keystate.set_pressed("Space");
+        // 没有 update 的帧不会清掉刚按下的状态
+        assert_eq!(game_loop.tick(&mut game, 10.0, &mut keystate), 0);
+        assert!(keystate.just_pressed("Space"));
+
+        game_loop.tick(&mut game, 20.0, &mut keystate);
+        assert!(!keystate.just_pressed("Space"));
+        assert_eq!(keystate.held_updates("Space"), Some(1));
+
+        // 按住不放时重复的 keydown 不算新的按下
+        keystate.set_pressed("Space");
+        assert!(!keystate.just_pressed("Space"));
+
+        keystate.set_released("Space");
+        assert!(keystate.just_released("Space"));
+        assert_eq!(keystate.held_updates("Space"), None);
+    }
+
+    #[test]
+    fn tapped_keys_release_themselves_after_the_given_updates() {
+        let mut keystate = KeyState::new();
+        keystate.tap(TOUCH_TAP, 2);
+
+        keystate.end_update();
+        assert!(keystate.is_pressed(TOUCH_TAP));
+        keystate.end_update();
+        assert!(!keystate.is_pressed(TOUCH_TAP));
+        assert!(keystate.just_released(TOUCH_TAP));
+    }
+
+    fn pointer(phase: PointerPhase, x: f64, y: f64, time: f64) -> PointerInput {
+        PointerInput {
+            phase,
+            id: 1,
+            x,
+            y,
+            time,
+            is_touch: true,
+        }
+    }
+
+    const TEST_BUTTON: &str = "Touch:Test";
+
+    #[test]
+    fn touch_controls_turn_gestures_and_buttons_into_keys() {
+        let mut controls = TouchControls::new(TouchLayout {
+            buttons: vec![TouchButton {
+                label: "Test",
+                code: TEST_BUTTON,
+                rect: Rect::new_from_x_y(457, 535, 123, 50),
+            }],
+            ..TouchLayout::default()
+        });
+        let mut keystate = KeyState::new();
+
+        controls.handle_pointer(
+            pointer(PointerPhase::Down, 300.0, 200.0, 0.0),
+            &mut keystate,
+        );
+        controls.handle_pointer(pointer(PointerPhase::Up, 305.0, 204.0, 80.0), &mut keystate);
+        assert!(keystate.just_pressed(TOUCH_TAP));
+
+        controls.handle_pointer(
+            pointer(PointerPhase::Down, 300.0, 200.0, 500.0),
+            &mut keystate,
+        );
+        controls.handle_pointer(
+            pointer(PointerPhase::Move, 302.0, 270.0, 600.0),
+            &mut keystate,
+        );
+        assert!(keystate.just_pressed(TOUCH_SWIPE_DOWN));
+
+        // 第一次触摸之后才显示按钮, 按钮按住多久对应的虚拟按键就按住多久
+        assert!(controls.is_visible());
+        controls.handle_pointer(
+            pointer(PointerPhase::Down, 500.0, 550.0, 900.0),
+            &mut keystate,
+        );
+        assert!(keystate.is_pressed(TEST_BUTTON));
+        controls.handle_pointer(pointer(PointerPhase::Up, 10.0, 10.0, 1500.0), &mut keystate);
+        assert!(!keystate.is_pressed(TEST_BUTTON));
+    }
+
+    #[test]
+    fn touch_controls_track_each_pointer_separately() {
+        let mut controls = TouchControls::new(TouchLayout::default());
+        let mut keystate = KeyState::new();
+        let second = |phase, x, y, time| PointerInput {
+            id: 2,
+            ..pointer(phase, x, y, time)
+        };
+
+        controls.handle_pointer(
+            pointer(PointerPhase::Down, 100.0, 100.0, 0.0),
+            &mut keystate,
+        );
+        controls.handle_pointer(
+            second(PointerPhase::Down, 400.0, 100.0, 10.0),
+            &mut keystate,
+        );
+        // 第二个手指松开不会影响第一个手指的手势
+        controls.handle_pointer(
+            second(PointerPhase::Cancel, 400.0, 100.0, 50.0),
+            &mut keystate,
+        );
+        controls.handle_pointer(pointer(PointerPhase::Up, 102.0, 101.0, 80.0), &mut keystate);
+
+        assert!(keystate.just_pressed(TOUCH_TAP));
+    }
+
+    #[test]
+    fn gamepad_buttons_and_stick_map_to_virtual_keys() {
+        let mut gamepads = GamepadInput::new();
+        let mut keystate = KeyState::new();
+        let mut pad = GamepadSnapshot {
+            index: 0,
+            id: "pad".into(),
+            standard: true,
+            buttons: vec![true],
+            axes: vec![0.0, 0.9],
+        };
+
+        assert_eq!(
+            gamepads.update(&[pad.clone()], &mut keystate),
+            vec![HotPlug::Connected("pad".into())]
+        );
+        assert!(keystate.just_pressed(GAMEPAD_A));
+        assert!(keystate.is_pressed(GAMEPAD_DOWN));
+
+        pad.buttons = vec![false];
+        gamepads.update(&[pad], &mut keystate);
+        assert!(keystate.just_released(GAMEPAD_A));
+
+        // 拔掉手柄时松开它按住的所有键
+        assert_eq!(
+            gamepads.update(&[], &mut keystate),
+            vec![HotPlug::Disconnected("pad".into())]
+        );
+        assert!(!keystate.is_pressed(GAMEPAD_DOWN));
+    }
+
+    #[test]
+    fn game_loop_replays_a_recorded_run_identically() {
+        let mut game = CountingGame {
+            seed: Some(7),
+            ..CountingGame::default()
+        };
+        let mut game_loop = GameLoop::new(0.0);
+        game_loop.enable_recording();
+        let mut keystate = KeyState::new();
+
+        // 帧间隔不固定, 有的帧里没有 update, 有的帧里有好几次
+        for (frame, timestamp) in [10.0, 30.0, 45.0, 100.0, 101.0, 160.0].iter().enumerate() {
+            if frame % 2 == 0 {
+                keystate.set_pressed("Space");
+            } else {
+                keystate.set_released("Space");
+            }
+            game_loop.tick(&mut game, *timestamp, &mut keystate);
+        }
+        let replay = game_loop.replay().unwrap();
+        assert_eq!(replay.seed, 7);
+
+        let mut replayed = CountingGame::default();
+        let mut replay_loop = GameLoop::new(0.0);
+        replay_loop.start_replay(&mut replayed, replay).unwrap();
+        let mut live_keystate = KeyState::new();
+        let mut timestamp = 0.0;
+        while replay_loop.is_replaying() {
+            timestamp += f64::from(FRAME_SIZE) + 0.1;
+            // 回放时玩家的按键不起作用
+            live_keystate.set_pressed("Space");
+            live_keystate.end_update();
+            live_keystate.set_released("Space");
+            replay_loop.tick(&mut replayed, timestamp, &mut live_keystate);
+        }
+
+        assert_eq!(replayed.seed, Some(7));
+        assert_eq!(replayed.jumps, game.jumps);
+        assert_eq!(replayed.updates, game.updates);
+    }
+
+    #[test]
+    fn game_loop_does_not_catch_up_on_time_spent_paused() {
+        let mut game = CountingGame::default();
+        let mut game_loop = GameLoop::new(0.0);
+        let mut keystate = KeyState::new();
+
+        keystate.set_pressed("Escape");
+        game_loop.tick(&mut game, 20.0, &mut keystate);
+        assert_eq!(game_loop.tick(&mut game, 5000.0, &mut keystate), 1);
+
+        keystate.set_released("Escape");
+        assert_eq!(game_loop.tick(&mut game, 5017.0, &mut keystate), 1);
+        assert!(!game.paused);
+        assert_eq!(game_loop.tick(&mut game, 5034.0, &mut keystate), 1);
+        assert_eq!(game.updates, 4);
+    }
+
+    #[test]
+    fn game_loop_clamps_long_frames_and_counts_dropped_updates() {
+        let mut game = CountingGame::default();
+        let mut game_loop = GameLoop::new(0.0);
+        let mut keystate = KeyState::new();
+
+        // 模拟标签页在后台放了 10 秒
+        let updates = game_loop.tick(&mut game, 10_000.0, &mut keystate);
+
+        assert_eq!(updates, MAX_UPDATES_PER_FRAME);
+        let metrics = game_loop.metrics();
+        assert_eq!(metrics.clamped_frames, 1);
+        // 10 秒大约是 600 个步长, 截断时的浮点误差最多少算一次
+        let accounted = u64::from(updates) + metrics.dropped_updates;
+        assert!((599..=600).contains(&accounted));
+        assert!(game_loop.alpha() < 1.0);
+    }
+
+    #[test]
+    fn game_loop_stops_after_a_failed_update() {
+        let mut game = CountingGame {
+            fail_at: Some(2),
+            ..CountingGame::default()
+        };
+        let mut game_loop = GameLoop::new(0.0);
+        let mut keystate = KeyState::new();
+
+        assert_eq!(game_loop.tick(&mut game, 100.0, &mut keystate), 2);
+        assert_eq!(game_loop.tick(&mut game, 200.0, &mut keystate), 0);
+
+        assert_eq!(game.updates, 2);
+        assert_eq!(
+            game_loop.failure(),
+            Some(&EngineError::Update("Update 2 failed".into()))
+        );
+    }
+
+    #[test]
+    fn error_screen_keeps_typed_errors_and_escapes_the_message() {
+        let asset_error = EngineError::from_error(
+            anyhow::Error::from(EngineError::Asset("<tiles.json>".into())),
+            EngineError::Initialize,
+        );
+        assert_eq!(asset_error, EngineError::Asset("<tiles.json>".into()));
+
+        let ui = error_screen_ui(&asset_error);
+        assert!(ui.contains("<p>Could not load assets: &lt;tiles.json&gt;</p>"));
+        assert!(ui.contains("<button id='retry'>Retry</button>"));
+    }
+
+    #[test]
+    fn game_loop_reports_alpha_between_fixed_steps() {
+        let mut game = CountingGame::default();
+        let mut game_loop = GameLoop::new(0.0);
+        let mut keystate = KeyState::new();
+
+        game_loop.tick(&mut game, f64::from(FRAME_SIZE * 1.5), &mut keystate);
+
+        assert_eq!(game.updates, 1);
+        assert!((game_loop.alpha() - 0.5).abs() < 0.001);
+    }
+
+    #[test]
+    fn debug_overlay_toggles_once_per_key_press() {
+        let mut game = CountingGame::default();
+        let mut game_loop = GameLoop::new(0.0);
+        let mut keystate = KeyState::new();
+        keystate.set_pressed("F3");
+
+        // 第一帧里没有 update, 按键留到下一次 update 才处理, 只切换一次
+        assert_eq!(game_loop.tick(&mut game, 10.0, &mut keystate), 0);
+        assert_eq!(game_loop.tick(&mut game, 20.0, &mut keystate), 1);
+        game_loop.tick(&mut game, 40.0, &mut keystate);
+        assert!(game_loop.debug_overlay().is_visible());
+
+        keystate.set_released("F3");
+        keystate.set_pressed("F3");
+        game_loop.tick(&mut game, 60.0, &mut keystate);
+        assert!(!game_loop.debug_overlay().is_visible());
+    }
+
+    #[test]
+    fn debug_overlay_draws_frame_rate_and_game_info() {
+        let mut overlay = DebugOverlay::new(true);
+        for _ in 0..61 {
+            overlay.record_frame(FrameStats {
+                frame_time: 17.0,
+                updates: 1,
+                ..FrameStats::default()
+            });
+        }
+        let renderer = RecordingRenderer::new();
+
+        overlay.draw(&renderer, LoopMetrics::default(), &["Obstacles 4".into()]);
+
+        let texts: Vec<String> = renderer
+            .commands()
+            .into_iter()
+            .filter_map(|command| match command {
+                DrawCommand::Text { text, .. } => Some(text),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(texts[0], "FPS 59");
+        assert_eq!(texts.last().unwrap(), "Obstacles 4");
+    }
+
+    #[test]
+    fn loading_screen_shows_progress_and_failures() {
+        let mut progress = LoadingProgress::default();
+        progress.begin("Loading", 4);
+        progress.succeed();
+        progress.fail("BG.png", "404".into());
+        let renderer = RecordingRenderer::new();
+
+        draw_loading_screen(&renderer, &progress);
+
+        let commands = renderer.commands();
+        assert!(commands.contains(&DrawCommand::FillRect {
+            rect: Rect::new_from_x_y(100, 280, 200, 30),
+            color: LOADING_BAR_COLOR.into(),
+        }));
+        let texts: Vec<String> = commands
+            .into_iter()
+            .filter_map(|command| match command {
+                DrawCommand::Text { text, .. } => Some(text),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(texts, ["Loading 2/4", "Could not load BG.png: 404"]);
+    }
+
+    #[test]
+    fn offset_renderer_shifts_destinations() {
+        let recording = RecordingRenderer::new();
+        let renderer = OffsetRenderer::new(&recording, Point { x: -2, y: 0 });
+
+        renderer.fill_rect(&Rect::new_from_x_y(10, 20, 5, 5), "red");
+
+        assert_eq!(
+            recording.take_commands(),
+            vec![DrawCommand::FillRect {
+                rect: Rect::new_from_x_y(8, 20, 5, 5),
+                color: "red".into(),
+            }]
+        );
+    }
 }
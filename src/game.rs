@@ -1,42 +1,114 @@
-use std::rc::Rc;
+use std::{collections::HashMap, rc::Rc};
 
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use futures::channel::mpsc::UnboundedReceiver;
-use rand::{thread_rng, Rng};
-use web_sys::HtmlImageElement;
+use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
 
 use crate::{
+    assets::{AssetLoader, AssetManifest, Assets},
     browser,
     engine::{
-        self, Audio, Cell, Game, Image, KeyState, Point, Rect, Renderer, Sheet, Sound, SpriteSheet,
+        self, Audio, Cell, EngineError, Game, Image, ImageHandle, KeyState, OffsetRenderer, Point,
+        Rect, Renderer, Sheet, Sound, SpriteSheet, TouchButton, TouchLayout, PAGE_HIDDEN,
     },
-    segment::{platform_and_stone, stone_and_platform},
+    ghost::{GhostActions, GhostInput, GhostRecorder, GhostRun, GhostRuns},
+    high_score::{HighScore, HighScoreTable},
+    input::{
+        self, Action, InputBindings, BOUNDING_BOX_KEY, GHOST_KEY, MUTE_KEY, SETTINGS_KEY,
+        TOUCH_JUMP_BUTTON, TOUCH_SLIDE_BUTTON, VOLUME_DOWN_KEY, VOLUME_UP_KEY,
+    },
+    segment::{create_segment, SegmentAssets, SegmentSheet},
+    sound::{Channel, SoundHandle, VolumeSettings},
+    storage::{LocalStorage, MemoryStorage, Storage},
 };
 
 use self::red_hat_boy_states::{
-    Falling, FallingEndState, Idle, Jumping, JumpingEndState, KnockedOut, RedHatBoyContext,
-    RedHatBoyState, Running, Sliding, SlidingEndState,
+    Airborne, AirborneEndState, Falling, FallingEndState, Idle, Jumping, JumpingEndState,
+    KnockedOut, RedHatBoyContext, RedHatBoyState, Running, RunningEndState, Sliding,
+    SlidingEndState, SoundEffect, SoundTable,
 };
 
+pub use self::red_hat_boy_states::JumpParams;
+
 const HEIGHT: i16 = 600;
 const TIMELINE_MINIMUM: i16 = 1000;
 const OBSTACLE_BUFFER: i16 = 20;
+const MAX_RANDOM_SEED: u64 = 1_000_000;
+const DAILY_SEED: &str = "daily";
+const DISTANCE_PER_POINT: i32 = 10;
+const HUD_FONT: &str = "24px 'Ken Future'";
+const MILLISECONDS_PER_DAY: f64 = 24.0 * 60.0 * 60.0 * 1000.0;
+const PAUSE_OVERLAY_COLOR: &str = "rgba(0, 0, 0, 0.5)";
+const RHB_BOUNDING_BOX_COLOR: &str = "#2980b9";
+const RHB_SPRITE_BOX_COLOR: &str = "#95a5a6";
+const BARRIER_BOUNDING_BOX_COLOR: &str = "#c0392b";
+const PLATFORM_BOUNDING_BOX_COLOR: &str = "#8e44ad";
+const LANDING_SURFACE_COLOR: &str = "#27ae60";
+const LANDING_SURFACE_HEIGHT: i16 = 3;
+const GHOST_ALPHA: f32 = 0.4;
+const VOLUME_STEP: f32 = 0.1;
+const PAUSED_MUSIC_VOLUME: f32 = 0.3;
+const MUSIC_FADE_SECONDS: f64 = 0.3;
+const MUSIC_CROSSFADE_SECONDS: f64 = 1.0;
+const KNOCK_OUT_STINGER_HZ: (f32, f32) = (440.0, 110.0);
+const KNOCK_OUT_STINGER_SECONDS: f64 = 0.6;
+// Button.svg 里按钮的位置, 和 styles.css 里的 background 偏移一致
+const BUTTON_FRAME: Rect = Rect::new_from_x_y(72, 60, 82, 33);
+const BUTTON_PRESSED_FRAME: Rect = Rect::new_from_x_y(244, 60, 82, 33);
+const BUTTON_FONT: &str = "20px 'Ken Future'";
+const TOUCH_BUTTONS: [TouchButton; 2] = [
+    TouchButton {
+        label: "Slide",
+        code: TOUCH_SLIDE_BUTTON,
+        rect: Rect::new_from_x_y(20, 535, 123, 50),
+    },
+    TouchButton {
+        label: "Jump",
+        code: TOUCH_JUMP_BUTTON,
+        rect: Rect::new_from_x_y(457, 535, 123, 50),
+    },
+];
 
 #[derive(Default)]
 pub struct WalkTheDog {
     machine: Option<WalkTheDogStateMachine>,
+    music: Option<Music>,
+    button_image: Option<ImageHandle>,
 }
 
 impl WalkTheDog {
     pub fn new() -> Self {
-        WalkTheDog { machine: None }
+        WalkTheDog {
+            machine: None,
+            music: None,
+            button_image: None,
+        }
+    }
+
+    fn with_walk(walk: Walk, music: Option<Music>) -> Self {
+        let mut walk_the_dog = WalkTheDog {
+            machine: Some(WalkTheDogStateMachine::new(walk)),
+            music,
+            button_image: None,
+        };
+        walk_the_dog.follow_music();
+        walk_the_dog
+    }
+
+    /// follow_music 让背景音乐跟上状态机当前的状态
+    fn follow_music(&mut self) {
+        if let (Some(music), Some(machine)) = (&mut self.music, &self.machine) {
+            music.follow(machine);
+        }
     }
 }
 
 enum WalkTheDogStateMachine {
     Ready(WalkTheDogState<Ready>),
     Walking(WalkTheDogState<Walking>),
+    Paused(WalkTheDogState<Paused>),
+    Settings(WalkTheDogState<Settings>),
     GameOver(WalkTheDogState<GameOver>),
 }
 
@@ -52,6 +124,18 @@ impl From<WalkTheDogState<Walking>> for WalkTheDogStateMachine {
     }
 }
 
+impl From<WalkTheDogState<Paused>> for WalkTheDogStateMachine {
+    fn from(state: WalkTheDogState<Paused>) -> Self {
+        WalkTheDogStateMachine::Paused(state)
+    }
+}
+
+impl From<WalkTheDogState<Settings>> for WalkTheDogStateMachine {
+    fn from(state: WalkTheDogState<Settings>) -> Self {
+        WalkTheDogStateMachine::Settings(state)
+    }
+}
+
 impl From<WalkTheDogState<GameOver>> for WalkTheDogStateMachine {
     fn from(state: WalkTheDogState<GameOver>) -> Self {
         WalkTheDogStateMachine::GameOver(state)
@@ -63,20 +147,100 @@ impl WalkTheDogStateMachine {
         WalkTheDogStateMachine::Ready(WalkTheDogState::new(walk))
     }
 
-    fn update(self, keystate: &KeyState) -> Self {
+    fn update(self, keystate: &KeyState) -> Result<Self> {
         log!("Keystate is {:#?}", keystate);
-        match self {
+        Ok(match self {
             WalkTheDogStateMachine::Ready(state) => state.update(keystate).into(),
-            WalkTheDogStateMachine::Walking(state) => state.update(keystate).into(),
+            WalkTheDogStateMachine::Walking(state) => state.update(keystate)?.into(),
+            WalkTheDogStateMachine::Paused(state) => state.update(keystate).into(),
+            WalkTheDogStateMachine::Settings(state) => state.update(keystate).into(),
             WalkTheDogStateMachine::GameOver(state) => state.update(keystate).into(),
+        })
+    }
+
+    fn pause(self) -> Self {
+        match self {
+            WalkTheDogStateMachine::Walking(state) => state.pause().into(),
+            _ => self,
+        }
+    }
+
+    fn walk(&self) -> &Walk {
+        match self {
+            WalkTheDogStateMachine::Ready(state) => &state.walk,
+            WalkTheDogStateMachine::Walking(state) => &state.walk,
+            WalkTheDogStateMachine::Paused(state) => &state.walk,
+            WalkTheDogStateMachine::Settings(state) => &state.walk,
+            WalkTheDogStateMachine::GameOver(state) => &state.walk,
+        }
+    }
+
+    /// restart 从任何状态回到 Ready, 并用 seed 开始新的一局
+    fn restart(self, seed: u64) -> WalkTheDogState<Ready> {
+        if matches!(
+            self,
+            WalkTheDogStateMachine::Settings(_) | WalkTheDogStateMachine::GameOver(_)
+        ) {
+            let _ = browser::hide_ui();
+        }
+        let walk = match self {
+            WalkTheDogStateMachine::Ready(state) => state.walk,
+            WalkTheDogStateMachine::Walking(state) => state.walk,
+            WalkTheDogStateMachine::Paused(state) => state.walk,
+            WalkTheDogStateMachine::Settings(state) => state.walk,
+            WalkTheDogStateMachine::GameOver(state) => state.walk,
+        };
+        WalkTheDogState::new(Walk::reset_with_seed(walk, seed))
+    }
+
+    fn walk_mut(&mut self) -> &mut Walk {
+        match self {
+            WalkTheDogStateMachine::Ready(state) => &mut state.walk,
+            WalkTheDogStateMachine::Walking(state) => &mut state.walk,
+            WalkTheDogStateMachine::Paused(state) => &mut state.walk,
+            WalkTheDogStateMachine::Settings(state) => &mut state.walk,
+            WalkTheDogStateMachine::GameOver(state) => &mut state.walk,
+        }
+    }
+
+    /// music 返回这个状态的背景音乐和音量的缩放, 暂停时音乐变小但不换曲子
+    fn music(&self) -> (MusicTrack, f32) {
+        match self {
+            WalkTheDogStateMachine::Ready(_) | WalkTheDogStateMachine::Settings(_) => {
+                (MusicTrack::Title, 1.0)
+            }
+            WalkTheDogStateMachine::Walking(_) => (MusicTrack::Running, 1.0),
+            WalkTheDogStateMachine::Paused(_) => (MusicTrack::Running, PAUSED_MUSIC_VOLUME),
+            WalkTheDogStateMachine::GameOver(_) => (MusicTrack::GameOver, 1.0),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            WalkTheDogStateMachine::Ready(_) => "Ready",
+            WalkTheDogStateMachine::Walking(_) => "Walking",
+            WalkTheDogStateMachine::Paused(_) => "Paused",
+            WalkTheDogStateMachine::Settings(_) => "Settings",
+            WalkTheDogStateMachine::GameOver(_) => "GameOver",
         }
     }
 
-    fn draw(&self, renderer: &Renderer) {
+    /// 只有在 Walking 状态下场景才在滚动, 其它状态画面是静止的, 直接画当前的位置(alpha 为 1.0)
+    fn draw(&self, renderer: &dyn Renderer, alpha: f32) -> Result<()> {
         match self {
-            WalkTheDogStateMachine::Ready(state) => state.draw(renderer),
-            WalkTheDogStateMachine::Walking(state) => state.draw(renderer),
-            WalkTheDogStateMachine::GameOver(state) => state.draw(renderer),
+            WalkTheDogStateMachine::Ready(state) => {
+                state.draw(renderer, 1.0)?;
+                state.draw_hint(renderer);
+                Ok(())
+            }
+            WalkTheDogStateMachine::Walking(state) => state.draw(renderer, alpha),
+            WalkTheDogStateMachine::Paused(state) => {
+                state.draw(renderer, 1.0)?;
+                state.draw_overlay(renderer);
+                Ok(())
+            }
+            WalkTheDogStateMachine::Settings(state) => state.draw(renderer, 1.0),
+            WalkTheDogStateMachine::GameOver(state) => state.draw(renderer, 1.0),
         }
     }
 }
@@ -87,8 +251,8 @@ struct WalkTheDogState<T> {
 }
 
 impl<T> WalkTheDogState<T> {
-    fn draw(&self, renderer: &Renderer) {
-        self.walk.draw(renderer)
+    fn draw(&self, renderer: &dyn Renderer, alpha: f32) -> Result<()> {
+        self.walk.draw(renderer, alpha)
     }
 }
 
@@ -100,13 +264,41 @@ impl WalkTheDogState<Ready> {
     }
     fn update(mut self, keystate: &KeyState) -> ReadyEndState {
         self.walk.boy.update();
-        if keystate.is_pressed("ArrowRight") {
+        if let Some(ghost) = &mut self.walk.ghost {
+            ghost.boy.update();
+        }
+        if self.walk.bindings.is_active(Action::Start, keystate) {
             ReadyEndState::Complete(self.start_running())
+        } else if keystate.just_pressed(SETTINGS_KEY) && !self.walk.is_replaying() {
+            match Settings::new(&self.walk.bindings, &self.walk.audio.volume_settings()) {
+                Ok(settings) => ReadyEndState::Settings(self.open_settings(settings)),
+                Err(err) => {
+                    error!("Could not open settings {:#?}", err);
+                    ReadyEndState::Continue(self)
+                }
+            }
         } else {
             ReadyEndState::Continue(self)
         }
     }
 
+    fn open_settings(self, settings: Settings) -> WalkTheDogState<Settings> {
+        WalkTheDogState {
+            walk: self.walk,
+            state: settings,
+        }
+    }
+
+    fn draw_hint(&self, renderer: &dyn Renderer) {
+        let hint = format!(
+            "Press {} to run, C for controls",
+            self.walk.bindings.label(Action::Start)
+        );
+        if let Err(err) = renderer.draw_text_with_font(&hint, &Point { x: 90, y: 250 }, HUD_FONT) {
+            error!("Could not draw hint {:#?}", err);
+        }
+    }
+
     fn start_running(mut self) -> WalkTheDogState<Walking> {
         self.run_right();
         WalkTheDogState {
@@ -117,12 +309,16 @@ impl WalkTheDogState<Ready> {
 
     fn run_right(&mut self) {
         self.walk.boy.run_right();
+        if let Some(ghost) = &mut self.walk.ghost {
+            ghost.boy.run_right();
+        }
     }
 }
 
 enum ReadyEndState {
     Complete(WalkTheDogState<Walking>),
     Continue(WalkTheDogState<Ready>),
+    Settings(WalkTheDogState<Settings>),
 }
 
 impl From<ReadyEndState> for WalkTheDogStateMachine {
@@ -130,38 +326,249 @@ impl From<ReadyEndState> for WalkTheDogStateMachine {
         match state {
             ReadyEndState::Complete(walking) => walking.into(),
             ReadyEndState::Continue(ready) => ready.into(),
+            ReadyEndState::Settings(settings) => settings.into(),
+        }
+    }
+}
+
+/// Settings 是改键和音量的界面: 点击某个操作的按钮后, 按下的下一个键就会绑定到这个操作上
+struct Settings {
+    events: SettingsEvents,
+    waiting_for: Option<Action>,
+}
+
+impl Settings {
+    fn new(bindings: &InputBindings, volume: &VolumeSettings) -> Result<Self> {
+        Ok(Settings {
+            events: draw_settings_ui(bindings, volume, None)?,
+            waiting_for: None,
+        })
+    }
+
+    fn done_pressed(&mut self) -> bool {
+        matches!(self.events.done.try_next(), Ok(Some(())))
+    }
+
+    fn bind_pressed(&mut self) -> Option<Action> {
+        self.events
+            .bind
+            .iter_mut()
+            .find_map(|(action, event)| match event.try_next() {
+                Ok(Some(())) => Some(*action),
+                _ => None,
+            })
+    }
+
+    /// volume_pressed 返回被点击的音量按钮对应的声道和音量的变化
+    fn volume_pressed(&mut self) -> Option<(Channel, f32)> {
+        self.events
+            .volume
+            .iter_mut()
+            .find_map(|(channel, step, event)| match event.try_next() {
+                Ok(Some(())) => Some((*channel, *step)),
+                _ => None,
+            })
+    }
+
+    fn redraw(&mut self, bindings: &InputBindings, volume: &VolumeSettings) -> Result<()> {
+        browser::hide_ui()?;
+        self.events = draw_settings_ui(bindings, volume, self.waiting_for)?;
+        Ok(())
+    }
+}
+
+impl WalkTheDogState<Settings> {
+    fn update(mut self, keystate: &KeyState) -> SettingsEndState {
+        if self.state.done_pressed() {
+            self.walk.boy.play_sound(SoundEffect::UiClick);
+            return SettingsEndState::Complete(self.close());
+        }
+
+        let new_key = keystate
+            .just_pressed_keys()
+            .find(|key| !input::is_virtual(key))
+            .map(String::from);
+        let mut changed = false;
+        if let Some(action) = self.state.bind_pressed() {
+            self.walk.boy.play_sound(SoundEffect::UiClick);
+            self.state.waiting_for = Some(action);
+            changed = true;
+        } else if let Some((channel, step)) = self.state.volume_pressed() {
+            let mut settings = self.walk.audio.volume_settings();
+            settings.set_volume(channel, settings.volume(channel) + step);
+            self.walk.set_volume_settings(settings);
+            self.walk.boy.play_sound(SoundEffect::UiClick);
+            changed = true;
+        } else if let (Some(action), Some(key)) = (self.state.waiting_for, new_key) {
+            // 不能用的按键不会绑定, 继续等玩家按下一个
+            match self.walk.bindings.bind(action, &key) {
+                Ok(()) => {
+                    if let Err(err) = self.walk.bindings.save(self.walk.storage.as_ref()) {
+                        error!("Could not save key bindings {:#?}", err);
+                    }
+                    self.state.waiting_for = None;
+                    changed = true;
+                }
+                Err(err) => log!("{}", err),
+            }
+        }
+
+        if changed {
+            if let Err(err) = self
+                .state
+                .redraw(&self.walk.bindings, &self.walk.audio.volume_settings())
+            {
+                error!("Could not redraw settings {:#?}", err);
+            }
+        }
+        SettingsEndState::Continue(self)
+    }
+
+    fn close(self) -> WalkTheDogState<Ready> {
+        let _ = browser::hide_ui();
+        WalkTheDogState::new(self.walk)
+    }
+}
+
+enum SettingsEndState {
+    Complete(WalkTheDogState<Ready>),
+    Continue(WalkTheDogState<Settings>),
+}
+
+impl From<SettingsEndState> for WalkTheDogStateMachine {
+    fn from(state: SettingsEndState) -> Self {
+        match state {
+            SettingsEndState::Complete(ready) => ready.into(),
+            SettingsEndState::Continue(settings) => settings.into(),
         }
     }
 }
 
+struct SettingsEvents {
+    bind: Vec<(Action, UnboundedReceiver<()>)>,
+    volume: Vec<(Channel, f32, UnboundedReceiver<()>)>,
+    done: UnboundedReceiver<()>,
+}
+
+fn draw_settings_ui(
+    bindings: &InputBindings,
+    volume: &VolumeSettings,
+    waiting_for: Option<Action>,
+) -> Result<SettingsEvents> {
+    browser::draw_ui(&settings_ui(bindings, volume, waiting_for))?;
+    let bind = Action::ALL
+        .iter()
+        .map(|action| {
+            browser::find_html_element_by_id(&bind_button_id(*action))
+                .map(|button| (*action, engine::add_click_handler(button)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let volume = Channel::ALL
+        .iter()
+        .flat_map(|channel| [(*channel, -VOLUME_STEP), (*channel, VOLUME_STEP)])
+        .map(|(channel, step)| {
+            browser::find_html_element_by_id(&volume_button_id(channel, step))
+                .map(|button| (channel, step, engine::add_click_handler(button)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let done = browser::find_html_element_by_id("settings_done").map(engine::add_click_handler)?;
+    Ok(SettingsEvents { bind, volume, done })
+}
+
+fn bind_button_id(action: Action) -> String {
+    format!("bind_{}", action.label().to_lowercase())
+}
+
+fn volume_button_id(channel: Channel, step: f32) -> String {
+    let direction = if step < 0.0 { "down" } else { "up" };
+    format!("volume_{}_{}", channel.label().to_lowercase(), direction)
+}
+
+fn settings_ui(
+    bindings: &InputBindings,
+    volume: &VolumeSettings,
+    waiting_for: Option<Action>,
+) -> String {
+    let rows: String = Action::ALL
+        .iter()
+        .map(|action| {
+            let label = if Some(*action) == waiting_for {
+                "Press a key".to_string()
+            } else {
+                bindings.label(*action)
+            };
+            format!(
+                "<tr><td>{}</td><td><button id='{}'>{}</button></td></tr>",
+                action.label(),
+                bind_button_id(*action),
+                label
+            )
+        })
+        .collect();
+    let volume_rows: String = Channel::ALL
+        .iter()
+        .map(|channel| {
+            format!(
+                "<tr><td>{}</td><td><button id='{}'>-</button>{}%<button id='{}'>+</button></td></tr>",
+                channel.label(),
+                volume_button_id(*channel, -VOLUME_STEP),
+                (volume.volume(*channel) * 100.0).round(),
+                volume_button_id(*channel, VOLUME_STEP)
+            )
+        })
+        .collect();
+    format!(
+        "<div id='settings' class='settings'><h2>Controls</h2><table>{}</table><h2>Volume</h2><table>{}</table><button id='settings_done'>Done</button></div>",
+        rows, volume_rows
+    )
+}
+
 struct Walking;
 
 impl WalkTheDogState<Walking> {
-    fn end_game(self) -> WalkTheDogState<GameOver> {
-        let receiver = browser::draw_ui("<button id='new_game'>New Game</button>")
+    fn end_game(mut self) -> Result<WalkTheDogState<GameOver>> {
+        let score = self.walk.score();
+        let rank = self.walk.record_run();
+
+        let receiver = browser::draw_ui(&game_over_ui(score, &self.walk.high_scores, rank))
             .and_then(|_unit| browser::find_html_element_by_id("new_game"))
-            .map(engine::add_click_handler)
-            .unwrap();
+            .map(engine::add_click_handler)?;
 
-        WalkTheDogState {
+        Ok(WalkTheDogState {
             walk: self.walk,
             state: GameOver {
                 new_game_event: receiver,
             },
-        }
+        })
     }
 
-    fn update(mut self, keystate: &KeyState) -> WalkingEndState {
-        if keystate.is_pressed("Space") {
-            self.walk.boy.jump();
+    fn pause(self) -> WalkTheDogState<Paused> {
+        WalkTheDogState {
+            walk: self.walk,
+            state: Paused,
         }
+    }
 
-        if keystate.is_pressed("ArrowDown") {
-            self.walk.boy.slide();
+    fn update(mut self, keystate: &KeyState) -> Result<WalkingEndState> {
+        if self.walk.bindings.just_pressed(Action::Pause, keystate) {
+            return Ok(WalkingEndState::Paused(self.pause()));
         }
 
+        // 跳跃键提前松开时截断上升速度, 轻点是小跳, 按住是完整的跳跃
+        let actions = GhostActions {
+            jump: self.walk.bindings.just_pressed(Action::Jump, keystate),
+            release_jump: self.walk.bindings.just_released(Action::Jump, keystate),
+            slide: self.walk.bindings.is_active(Action::Slide, keystate),
+        };
+        self.walk.ghost_recorder.record(actions);
+        self.walk.boy.perform(actions);
         self.walk.boy.update();
+        if let Some(ghost) = &mut self.walk.ghost {
+            ghost.update();
+        }
+        self.walk.distance += i32::from(self.walk.boy.walking_speed());
         let walking_spped = self.walk.velocity();
+        self.walk.scrolled = walking_spped;
         let [first_background, second_background] = &mut self.walk.backgrounds;
         first_background.move_horizontally(walking_spped);
         second_background.move_horizontally(walking_spped);
@@ -177,15 +584,20 @@ impl WalkTheDogState<Walking> {
         // retain函数: 保留符合条件的对象
         self.walk.obstacles.retain(|obstacle| obstacle.right() > 0);
 
-        self.walk.obstacles.iter_mut().for_each(|obstacle| {
-            obstacle.move_horizontally(walking_spped);
-            obstacle.check_intersection(&mut self.walk.boy);
-        });
-
-        self.walk.obstacles.iter_mut().for_each(|obstacle| {
-            obstacle.move_horizontally(walking_spped);
-            obstacle.check_intersection(&mut self.walk.boy);
-        });
+        self.walk
+            .obstacles
+            .iter_mut()
+            .try_for_each(|obstacle| -> Result<()> {
+                obstacle.move_horizontally(walking_spped);
+                obstacle.check_intersection(&mut self.walk.boy)?;
+                if let Some(ghost) = &mut self.walk.ghost {
+                    obstacle.check_intersection(&mut ghost.boy)?;
+                }
+                Ok(())
+            })?;
+
+        // 幽灵撞倒或者录像放完之后就不再显示
+        self.walk.ghost = self.walk.ghost.take().filter(Ghost::is_running);
 
         if self.walk.timeline < TIMELINE_MINIMUM {
             self.walk.generate_next_segment();
@@ -194,16 +606,37 @@ impl WalkTheDogState<Walking> {
         }
 
         if self.walk.knocked_out() {
-            WalkingEndState::Complete(self.end_game())
+            Ok(WalkingEndState::Complete(self.end_game()?))
         } else {
-            WalkingEndState::Continue(self)
+            Ok(WalkingEndState::Continue(self))
         }
     }
 }
 
+fn game_over_ui(score: i32, high_scores: &HighScoreTable, rank: Option<usize>) -> String {
+    let entries: String = high_scores
+        .entries()
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let class = if Some(index) == rank {
+                " class='new'"
+            } else {
+                ""
+            };
+            format!("<li{}>{} (seed {})</li>", class, entry.score, entry.seed)
+        })
+        .collect();
+    format!(
+        "<div id='game_over'><p class='score'>Score {}</p><ol class='high_scores'>{}</ol><button id='new_game'>New Game</button></div>",
+        score, entries
+    )
+}
+
 enum WalkingEndState {
     Complete(WalkTheDogState<GameOver>),
     Continue(WalkTheDogState<Walking>),
+    Paused(WalkTheDogState<Paused>),
 }
 
 impl From<WalkingEndState> for WalkTheDogStateMachine {
@@ -211,6 +644,50 @@ impl From<WalkingEndState> for WalkTheDogStateMachine {
         match state {
             WalkingEndState::Complete(walking) => walking.into(),
             WalkingEndState::Continue(ready) => ready.into(),
+            WalkingEndState::Paused(paused) => paused.into(),
+        }
+    }
+}
+
+/// Paused 只响应新按下的暂停键, 按住暂停键不会在暂停和继续之间来回切换
+struct Paused;
+
+impl WalkTheDogState<Paused> {
+    fn update(self, keystate: &KeyState) -> PausedEndState {
+        if self.walk.bindings.just_pressed(Action::Pause, keystate) {
+            PausedEndState::Complete(self.resume())
+        } else {
+            PausedEndState::Continue(self)
+        }
+    }
+
+    fn resume(self) -> WalkTheDogState<Walking> {
+        WalkTheDogState {
+            walk: self.walk,
+            state: Walking,
+        }
+    }
+
+    fn draw_overlay(&self, renderer: &dyn Renderer) {
+        renderer.fill_rect(&Rect::new_from_x_y(0, 0, 600, HEIGHT), PAUSE_OVERLAY_COLOR);
+        if let Err(err) =
+            renderer.draw_text_with_font("Paused", &Point { x: 240, y: 300 }, HUD_FONT)
+        {
+            error!("Could not draw pause overlay {:#?}", err);
+        }
+    }
+}
+
+enum PausedEndState {
+    Complete(WalkTheDogState<Walking>),
+    Continue(WalkTheDogState<Paused>),
+}
+
+impl From<PausedEndState> for WalkTheDogStateMachine {
+    fn from(state: PausedEndState) -> Self {
+        match state {
+            PausedEndState::Complete(walking) => walking.into(),
+            PausedEndState::Continue(paused) => paused.into(),
         }
     }
 }
@@ -235,6 +712,7 @@ impl WalkTheDogState<GameOver> {
 
     fn update(mut self, _keystate: &KeyState) -> GameOverEndState {
         if self.state.new_game_pressed() {
+            self.walk.boy.play_sound(SoundEffect::UiClick);
             GameOverEndState::Complete(self.new_game())
         } else {
             GameOverEndState::Continue(self)
@@ -258,102 +736,355 @@ impl From<GameOverEndState> for WalkTheDogStateMachine {
 
 #[async_trait(?Send)]
 impl Game for WalkTheDog {
-    async fn initialize(&self) -> Result<Box<dyn Game>> {
+    async fn initialize(&self, loader: &AssetLoader) -> Result<Box<dyn Game>> {
         match self.machine {
             None => {
-                let json = browser::fetch_json("rhb.json").await?;
-                let sheet: Sheet = serde_wasm_bindgen::from_value(json)
-                    .expect("Could not convert rhb.json into a Sheet structure");
-
-                let image = engine::load_image("rhb.png").await?;
-                let audio = Audio::new()?;
-                let sound = audio.load_sound("SFX_Jump_23.mp3").await?;
-                let backgound_music = audio.load_sound("background_song.mp3").await?;
-                audio.play_looping_sound(&backgound_music)?;
-                let rhb = RedHatBoy::new(sheet, image, audio, sound);
-                let stone = engine::load_image("Stone.png").await?;
-                let platform_sheet = browser::fetch_json("tiles.json").await?;
-                let platform_sheet: Sheet = serde_wasm_bindgen::from_value(platform_sheet)
-                    .expect("Could not convert tiles.json into a Sheet structure");
-
-                let sprite_sheet = Rc::new(SpriteSheet::new(
-                    platform_sheet,
-                    engine::load_image("tiles.png").await?,
-                ));
-                let background = engine::load_image("BG.png").await?;
-                let background_width = background.width() as i16;
-
-                let starting_obstacles = stone_and_platform(stone.clone(), sprite_sheet.clone(), 0);
-                let timeline = right_most(&starting_obstacles);
-                let machine = WalkTheDogStateMachine::new(Walk {
-                    obstacle_sheet: sprite_sheet.clone(),
-                    boy: rhb,
-                    backgrounds: [
-                        Image::new(background.clone(), Point { x: 0, y: 0 }),
-                        Image::new(
-                            background,
-                            Point {
-                                x: background_width,
-                                y: 0,
-                            },
-                        ),
-                    ],
-                    obstacles: starting_obstacles,
-                    stone,
-                    timeline,
-                });
-                Ok(Box::new(WalkTheDog {
-                    machine: Some(machine),
-                }))
+                let audio = loader.audio().clone();
+                let assets = loader.load("Loading", &asset_manifest()).await?;
+
+                let sounds = SoundEffect::ALL
+                    .iter()
+                    .map(|effect| Ok((*effect, assets.sound(effect.file())?)))
+                    .collect::<Result<HashMap<_, _>>>()?;
+                let music = Music::new(audio.clone(), &assets)?;
+
+                let platform_sheet: Sheet = loader.json(&assets, "tiles.json")?;
+                let sprite_sheet =
+                    Rc::new(SpriteSheet::new(platform_sheet, assets.image("tiles.png")?));
+                // 障碍物用到哪些图片要读了 segments.json 之后才知道, 所以分两次加载
+                let segments: SegmentSheet = loader.json(&assets, "segments.json")?;
+                let barrier_manifest = segments
+                    .barrier_images()
+                    .iter()
+                    .fold(AssetManifest::new(), |manifest, name| manifest.image(name));
+                let barrier_assets = loader.load("Loading obstacles", &barrier_manifest).await?;
+                let images = segments
+                    .barrier_images()
+                    .iter()
+                    .map(|name| Ok((name.to_string(), barrier_assets.image(name)?)))
+                    .collect::<Result<HashMap<_, _>>>()?;
+                let segment_assets = SegmentAssets {
+                    sprite_sheet,
+                    images,
+                };
+                segments.validate(&segment_assets)?;
+                let walk_assets = WalkAssets {
+                    rhb_sheet: loader.json(&assets, "rhb.json")?,
+                    rhb_image: assets.image("rhb.png")?,
+                    sounds: Rc::new(sounds),
+                    background: assets.image("BG.png")?,
+                    segments,
+                    segment_assets,
+                };
+
+                let storage: Rc<dyn Storage> = match LocalStorage::new() {
+                    Ok(storage) => Rc::new(storage),
+                    Err(err) => {
+                        error!("Falling back to in-memory storage {:#?}", err);
+                        Rc::new(MemoryStorage::new())
+                    }
+                };
+                let seed_source = SeedSource::from_query(
+                    browser::query_param("seed")?.as_deref(),
+                    js_sys::Date::now(),
+                );
+                let walk = Walk::new(walk_assets, audio, storage, seed_source);
+                let mut walk_the_dog = WalkTheDog::with_walk(walk, Some(music));
+                walk_the_dog.button_image = Some(assets.image("Button.svg")?);
+                Ok(Box::new(walk_the_dog))
             }
             Some(_) => Err(anyhow!("Error: Game is already initialized")),
         }
     }
 
-    fn update(&mut self, keystate: &KeyState) {
-        if let Some(machine) = self.machine.take() {
-            self.machine.replace(machine.update(keystate));
+    fn update(&mut self, keystate: &KeyState) -> Result<()> {
+        // 页面被隐藏(比如切换了标签页)时自动暂停
+        let page_hidden = keystate.just_pressed(PAGE_HIDDEN);
+        let toggle_bounding_boxes = keystate.just_pressed(BOUNDING_BOX_KEY);
+        let toggle_ghost = keystate.just_pressed(GHOST_KEY);
+
+        if let Some(mut machine) = self.machine.take() {
+            if page_hidden {
+                machine = machine.pause();
+            }
+            if toggle_bounding_boxes {
+                let walk = machine.walk_mut();
+                walk.show_bounding_boxes = !walk.show_bounding_boxes;
+            }
+            if toggle_ghost {
+                let walk = machine.walk_mut();
+                walk.show_ghost = !walk.show_ghost;
+            }
+            // 改键时按下的键都是要绑定的键, 回放时的按键是录像里的, 都不调整音量
+            if !matches!(machine, WalkTheDogStateMachine::Settings(_))
+                && !machine.walk().is_replaying()
+            {
+                machine.walk().handle_volume_keys(keystate);
+            }
+            self.machine.replace(machine.update(keystate)?);
         }
+        self.follow_music();
 
         assert!(self.machine.is_some());
+        Ok(())
     }
 
-    fn draw(&self, renderer: &Renderer) {
+    fn is_paused(&self) -> bool {
+        matches!(self.machine, Some(WalkTheDogStateMachine::Paused(_)))
+    }
+
+    // 设置界面里的操作是 DOM 事件, 录不进录像, 打开设置时这一局的录像就结束了
+    fn replay_seed(&self) -> Option<u64> {
+        match &self.machine {
+            Some(WalkTheDogStateMachine::GameOver(_))
+            | Some(WalkTheDogStateMachine::Settings(_))
+            | None => None,
+            Some(machine) => Some(machine.walk().seed),
+        }
+    }
+
+    fn replay_context(&self) -> serde_json::Value {
+        self.machine
+            .as_ref()
+            .and_then(|machine| serde_json::to_value(&machine.walk().bindings).ok())
+            .unwrap_or_default()
+    }
+
+    fn start_replay(&mut self, seed: u64, context: &serde_json::Value) -> Result<()> {
+        let Some(machine) = self.machine.take() else {
+            return Err(anyhow!("Error: Game is not initialized"));
+        };
+        let mut ready = machine.restart(seed);
+        // 没有记录按键绑定的旧录像只能用玩家现在的按键回放
+        if !context.is_null() {
+            let bindings: InputBindings = serde_json::from_value(context.clone())
+                .map_err(|err| anyhow!("Could not read replay bindings: {}", err))?;
+            ready.walk.start_replay(bindings);
+        }
+        self.machine.replace(ready.into());
+        self.follow_music();
+        Ok(())
+    }
+
+    fn stop_replay(&mut self) {
+        if let Some(machine) = &mut self.machine {
+            machine.walk_mut().stop_replay();
+        }
+    }
+
+    fn touch_layout(&self) -> TouchLayout {
+        TouchLayout {
+            buttons: TOUCH_BUTTONS.to_vec(),
+            image: self.button_image.clone(),
+            frame: BUTTON_FRAME,
+            pressed_frame: BUTTON_PRESSED_FRAME,
+            font: BUTTON_FONT,
+        }
+    }
+
+    fn debug_info(&self) -> Vec<String> {
+        let Some(machine) = &self.machine else {
+            return Vec::new();
+        };
+        let walk = machine.walk();
+        vec![
+            format!("Game {}", machine.name()),
+            format!("RHB {}", walk.boy.state_name()),
+            format!(
+                "Ghost {}",
+                walk.ghost
+                    .as_ref()
+                    .map_or("-", |ghost| ghost.boy.state_name())
+            ),
+            format!("Obstacles {}", walk.obstacles.len()),
+            format!(
+                "Audio {}",
+                if walk.audio.is_suspended() {
+                    "suspended"
+                } else {
+                    "running"
+                }
+            ),
+        ]
+    }
+
+    fn draw(&self, renderer: &dyn Renderer, alpha: f32) -> Result<()> {
         renderer.clear(&Rect {
             position: Point { x: 0, y: 0 },
             width: 600,
             height: 600,
         });
 
-        if let Some(machine) = &self.machine {
-            machine.draw(renderer);
+        match &self.machine {
+            Some(machine) => machine.draw(renderer, alpha),
+            None => Ok(()),
         }
     }
 }
 
+/// asset_manifest 列出游戏开始前需要加载的所有资源, 障碍物的图片由 segments.json 决定, 之后再加载
+fn asset_manifest() -> AssetManifest {
+    let manifest = AssetManifest::new()
+        .json("rhb.json")
+        .image("rhb.png")
+        .json("tiles.json")
+        .image("tiles.png")
+        .json("segments.json")
+        .image("BG.png")
+        .image("Button.svg");
+    let manifest = SoundEffect::ALL
+        .iter()
+        .fold(manifest, |manifest, effect| manifest.sound(effect.file()));
+    MusicTrack::ALL
+        .iter()
+        .fold(manifest, |manifest, track| manifest.sound(track.file()))
+}
+
+/// WalkAssets 是创建 Walk 需要的资源, 浏览器里由 AssetLoader 加载, 原生测试里直接构造
+struct WalkAssets {
+    rhb_sheet: Sheet,
+    rhb_image: ImageHandle,
+    sounds: SoundTable,
+    background: ImageHandle,
+    segments: SegmentSheet,
+    segment_assets: SegmentAssets,
+}
+
 pub struct Walk {
-    obstacle_sheet: Rc<SpriteSheet>,
     boy: RedHatBoy,
     backgrounds: [Image; 2],
     obstacles: Vec<Box<dyn Obstacle>>,
 
-    stone: HtmlImageElement,
+    segments: SegmentSheet,
+    segment_assets: SegmentAssets,
     timeline: i16,
+    // 上一次 update 里场景滚动的距离, 绘制时用它在上一次和这一次的位置之间插值
+    scrolled: i16,
+    distance: i32,
+    show_bounding_boxes: bool,
+    bindings: InputBindings,
+    high_scores: HighScoreTable,
+    storage: Rc<dyn Storage>,
+    audio: Audio,
+    // 回放录像时换成录像里的按键绑定, 这里保存玩家自己的, 回放结束后换回来
+    player_bindings: Option<InputBindings>,
+
+    ghost_runs: GhostRuns,
+    ghost_recorder: GhostRecorder,
+    ghost: Option<Ghost>,
+    show_ghost: bool,
+
+    seed_source: SeedSource,
+    seed: u64,
+    rng: StdRng,
 }
 
 impl Walk {
+    /// new 从 storage 里读出最高分, 按键, 音量和幽灵的录像, 用 seed_source 开始第一局
+    fn new(
+        assets: WalkAssets,
+        audio: Audio,
+        storage: Rc<dyn Storage>,
+        seed_source: SeedSource,
+    ) -> Self {
+        let high_scores = HighScoreTable::load(storage.as_ref()).unwrap_or_else(|err| {
+            error!("Could not load high scores {:#?}", err);
+            HighScoreTable::default()
+        });
+        let bindings = InputBindings::load(storage.as_ref()).unwrap_or_else(|err| {
+            error!("Could not load key bindings {:#?}", err);
+            InputBindings::default()
+        });
+        audio.set_volume_settings(
+            VolumeSettings::load(storage.as_ref()).unwrap_or_else(|err| {
+                error!("Could not load volume settings {:#?}", err);
+                VolumeSettings::default()
+            }),
+        );
+        let ghost_runs = GhostRuns::load(storage.as_ref()).unwrap_or_else(|err| {
+            error!("Could not load ghost runs {:#?}", err);
+            GhostRuns::default()
+        });
+
+        let rhb = RedHatBoy::new(
+            assets.rhb_sheet,
+            assets.rhb_image,
+            audio.clone(),
+            assets.sounds,
+        );
+        let seed = seed_source.next_seed();
+        let ghost = Ghost::for_seed(&rhb, &ghost_runs, seed);
+        let starting_obstacles = starting_obstacles(&assets.segments, &assets.segment_assets);
+        let timeline = right_most(&starting_obstacles).unwrap_or(0);
+        let background_width = assets.background.width();
+        Walk {
+            boy: rhb,
+            backgrounds: [
+                Image::new(assets.background.clone(), Point { x: 0, y: 0 }),
+                Image::new(
+                    assets.background,
+                    Point {
+                        x: background_width,
+                        y: 0,
+                    },
+                ),
+            ],
+            obstacles: starting_obstacles,
+            segments: assets.segments,
+            segment_assets: assets.segment_assets,
+            timeline,
+            scrolled: 0,
+            distance: 0,
+            show_bounding_boxes: false,
+            bindings,
+            high_scores,
+            storage,
+            audio,
+            player_bindings: None,
+            ghost_runs,
+            ghost_recorder: GhostRecorder::default(),
+            ghost,
+            show_ghost: true,
+            seed_source,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
     fn reset(walk: Self) -> Self {
-        let starting_obstacles =
-            stone_and_platform(walk.stone.clone(), walk.obstacle_sheet.clone(), 0);
-        let timeline = right_most(&starting_obstacles);
+        let seed = walk.seed_source.next_seed();
+        Walk::reset_with_seed(walk, seed)
+    }
+
+    fn reset_with_seed(walk: Self, seed: u64) -> Self {
+        let starting_obstacles = starting_obstacles(&walk.segments, &walk.segment_assets);
+        let timeline = right_most(&starting_obstacles).unwrap_or(0);
+
+        let boy = RedHatBoy::reset(walk.boy);
+        let ghost = Ghost::for_seed(&boy, &walk.ghost_runs, seed);
 
         Walk {
-            obstacle_sheet: walk.obstacle_sheet,
-            boy: RedHatBoy::reset(walk.boy),
+            boy,
             backgrounds: walk.backgrounds,
             obstacles: starting_obstacles,
-            stone: walk.stone,
+            segments: walk.segments,
+            segment_assets: walk.segment_assets,
             timeline,
+            scrolled: 0,
+            distance: 0,
+            show_bounding_boxes: walk.show_bounding_boxes,
+            bindings: walk.bindings,
+            high_scores: walk.high_scores,
+            storage: walk.storage,
+            audio: walk.audio,
+            player_bindings: walk.player_bindings,
+            ghost_runs: walk.ghost_runs,
+            ghost_recorder: GhostRecorder::default(),
+            ghost,
+            show_ghost: walk.show_ghost,
+            seed_source: walk.seed_source,
+            seed,
+            rng: StdRng::seed_from_u64(seed),
         }
     }
 
@@ -361,48 +1092,410 @@ impl Walk {
         self.boy.knocked_out()
     }
 
+    fn is_replaying(&self) -> bool {
+        self.player_bindings.is_some()
+    }
+
+    fn start_replay(&mut self, bindings: InputBindings) {
+        let player_bindings = std::mem::replace(&mut self.bindings, bindings);
+        self.player_bindings.get_or_insert(player_bindings);
+    }
+
+    fn stop_replay(&mut self) {
+        if let Some(bindings) = self.player_bindings.take() {
+            self.bindings = bindings;
+        }
+    }
+
+    /// record_run 保存这一局的分数和幽灵录像, 返回分数的名次.
+    /// 回放的是别人上传的录像, 什么也不保存
+    fn record_run(&mut self) -> Option<usize> {
+        if self.is_replaying() {
+            return None;
+        }
+        let score = self.score();
+        let rank = self.high_scores.insert(HighScore {
+            score,
+            seed: self.seed,
+        });
+        if let Err(err) = self.high_scores.save(self.storage.as_ref()) {
+            error!("Could not save high scores {:#?}", err);
+        }
+        let kept = self.ghost_runs.insert(GhostRun {
+            seed: self.seed,
+            score,
+            actions: self.ghost_recorder.actions().to_vec(),
+        });
+        if kept {
+            if let Err(err) = self.ghost_runs.save(self.storage.as_ref()) {
+                error!("Could not save ghost runs {:#?}", err);
+            }
+        }
+        rank
+    }
+
+    /// M 切换静音, - 和 = 调整总音量, 音乐和音效的音量在设置界面里调整
+    fn handle_volume_keys(&self, keystate: &KeyState) {
+        let mut settings = self.audio.volume_settings();
+        if keystate.just_pressed(MUTE_KEY) {
+            settings.muted = !settings.muted;
+        }
+        if keystate.just_pressed(VOLUME_DOWN_KEY) {
+            settings.set_volume(Channel::Master, settings.master - VOLUME_STEP);
+        }
+        if keystate.just_pressed(VOLUME_UP_KEY) {
+            settings.set_volume(Channel::Master, settings.master + VOLUME_STEP);
+        }
+        self.set_volume_settings(settings);
+    }
+
+    /// set_volume_settings 应用新的音量, 改过的设置会保存下来
+    fn set_volume_settings(&self, settings: VolumeSettings) {
+        if settings == self.audio.volume_settings() {
+            return;
+        }
+        self.audio.set_volume_settings(settings);
+        if let Err(err) = settings.save(self.storage.as_ref()) {
+            error!("Could not save volume settings {:#?}", err);
+        }
+    }
+
     fn velocity(&self) -> i16 {
         -self.boy.walking_speed()
     }
 
+    fn score(&self) -> i32 {
+        self.distance / DISTANCE_PER_POINT
+    }
+
     fn generate_next_segment(&mut self) {
-        let mut rng = thread_rng();
-        let next_segment = rng.gen_range(0..2);
-
-        let mut next_obstacles = match next_segment {
-            0 => stone_and_platform(
-                self.stone.clone(),
-                self.obstacle_sheet.clone(),
-                self.timeline + OBSTACLE_BUFFER,
-            ),
-            1 => platform_and_stone(
-                self.stone.clone(),
-                self.obstacle_sheet.clone(),
-                self.timeline + OBSTACLE_BUFFER,
-            ),
-            _ => vec![],
-        };
+        if self.segments.segments.is_empty() {
+            return;
+        }
+        let next_segment = self.rng.gen_range(0..self.segments.segments.len());
 
-        self.timeline = right_most(&next_obstacles);
+        let mut next_obstacles = create_segment(
+            &self.segments.segments[next_segment],
+            &self.segment_assets,
+            self.timeline + OBSTACLE_BUFFER,
+        );
+
+        // 片段是空的话保留原来的 timeline, 不然下一个片段会从头开始排
+        if let Some(right) = right_most(&next_obstacles) {
+            self.timeline = right;
+        }
         self.obstacles.append(&mut next_obstacles);
     }
 
-    fn draw(&self, renderer: &Renderer) {
-        self.backgrounds.iter().for_each(|background| {
-            background.draw(renderer);
-        });
-        self.boy.draw(renderer);
+    fn draw(&self, renderer: &dyn Renderer, alpha: f32) -> Result<()> {
+        // 所有滚动的东西每次 update 都移动同样的距离, 所以把这一次的位置往回退 (1 - alpha) 次滚动,
+        // 就是在上一次和这一次的位置之间插值. 刷新率高于 60 帧的屏幕上画面不会一顿一顿的
+        let scrolled = OffsetRenderer::new(
+            renderer,
+            Point {
+                x: (f32::from(-self.scrolled) * (1.0 - alpha)).round() as i16,
+                y: 0,
+            },
+        );
+        self.backgrounds
+            .iter()
+            .try_for_each(|background| background.draw(&scrolled))?;
+        if let Some(ghost) = self.ghost.as_ref().filter(|_| self.show_ghost) {
+            ghost.draw(renderer)?;
+        }
+        self.boy.draw(renderer)?;
         self.obstacles
             .iter()
-            .for_each(|obstacle| obstacle.draw(renderer));
+            .try_for_each(|obstacle| obstacle.draw(&scrolled))?;
+
+        if self.show_bounding_boxes {
+            self.boy.draw_bounding_boxes(renderer)?;
+            self.obstacles
+                .iter()
+                .for_each(|obstacle| obstacle.draw_bounding_boxes(&scrolled));
+        }
+
+        if let Err(err) = self.draw_hud(renderer) {
+            error!("Could not draw HUD {:#?}", err);
+        }
+        Ok(())
+    }
+
+    fn draw_hud(&self, renderer: &dyn Renderer) -> Result<()> {
+        renderer.draw_text_with_font(
+            &format!("Score {}", self.score()),
+            &Point { x: 10, y: 30 },
+            HUD_FONT,
+        )?;
+        renderer.draw_text_with_font(
+            &format!("Seed {}", self.seed),
+            &Point { x: 10, y: 590 },
+            HUD_FONT,
+        )?;
+        if self.audio.volume_settings().muted {
+            renderer.draw_text_with_font("Muted", &Point { x: 480, y: 30 }, HUD_FONT)?;
+        }
+        if self.audio.is_suspended() {
+            renderer.draw_text_with_font(
+                "Tap or press a key for sound",
+                &Point { x: 10, y: 60 },
+                HUD_FONT,
+            )?;
+        }
+        Ok(())
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MusicTrack {
+    Title,
+    Running,
+    GameOver,
+}
+
+impl MusicTrack {
+    const ALL: [MusicTrack; 3] = [MusicTrack::Title, MusicTrack::Running, MusicTrack::GameOver];
+
+    // 目前只有一首背景音乐, 不同的状态用音量和速度来区分, 同一首曲子换状态时不会从头开始.
+    // 以后有了新的曲子只要改这里
+    fn file(&self) -> &'static str {
+        match self {
+            MusicTrack::Title | MusicTrack::Running | MusicTrack::GameOver => "background_song.mp3",
+        }
+    }
+
+    fn volume(&self) -> f32 {
+        match self {
+            MusicTrack::Title => 0.6,
+            MusicTrack::Running => 1.0,
+            MusicTrack::GameOver => 0.5,
+        }
+    }
+
+    fn playback_rate(&self) -> f32 {
+        match self {
+            MusicTrack::Title | MusicTrack::Running => 1.0,
+            MusicTrack::GameOver => 0.8,
+        }
+    }
+}
+
+/// MusicCue 是游戏状态变化之后背景音乐要做的事
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MusicCue {
+    Keep,
+    /// 换一首曲子: 旧的淡出, 新的从头淡入
+    Crossfade,
+    /// 还是同一首曲子, 接着播放, 只调整音量和速度
+    Adjust {
+        volume: f32,
+        playback_rate: f32,
+    },
+}
+
+impl MusicCue {
+    /// between 比较正在播放的曲子和音量缩放, 决定怎么切换到 next
+    fn between(current: Option<(MusicTrack, f32)>, next: (MusicTrack, f32)) -> Self {
+        let (track, volume_scale) = next;
+        match current {
+            Some(current) if current == next => MusicCue::Keep,
+            Some((current_track, _)) if current_track.file() == track.file() => MusicCue::Adjust {
+                volume: track.volume() * volume_scale,
+                playback_rate: track.playback_rate(),
+            },
+            _ => MusicCue::Crossfade,
+        }
+    }
+}
+
+/// Music 跟着游戏状态切换背景音乐, 换曲子时旧的淡出, 新的同时淡入
+struct Music {
+    audio: Audio,
+    songs: HashMap<&'static str, Sound>,
+    current: Option<(MusicTrack, SoundHandle)>,
+    volume_scale: f32,
+}
+
+impl Music {
+    fn new(audio: Audio, assets: &Assets) -> Result<Self> {
+        let songs = MusicTrack::ALL
+            .iter()
+            .map(|track| Ok((track.file(), assets.sound(track.file())?)))
+            .collect::<Result<HashMap<_, _>>>()?;
+        Ok(Music {
+            audio,
+            songs,
+            current: None,
+            volume_scale: 1.0,
+        })
+    }
+
+    fn follow(&mut self, machine: &WalkTheDogStateMachine) {
+        let next = machine.music();
+        let current = self
+            .current
+            .as_ref()
+            .map(|(track, _handle)| (*track, self.volume_scale));
+        if current.map(|(track, _)| track) == Some(MusicTrack::Running)
+            && next.0 == MusicTrack::GameOver
+        {
+            self.play_knock_out_stinger();
+        }
+        let result = match MusicCue::between(current, next) {
+            MusicCue::Keep => Ok(()),
+            MusicCue::Crossfade => self.crossfade_to(next),
+            MusicCue::Adjust {
+                volume,
+                playback_rate,
+            } => self.adjust(next, volume, playback_rate),
+        };
+        if let Err(err) = result {
+            error!("Could not play {:?} music {:#?}", next.0, err);
+        }
+    }
+
+    fn crossfade_to(&mut self, (track, volume_scale): (MusicTrack, f32)) -> Result<()> {
+        if let Some((_track, handle)) = self.current.take() {
+            handle.fade_out(MUSIC_CROSSFADE_SECONDS)?;
+        }
+        let song = self
+            .songs
+            .get(track.file())
+            .ok_or_else(|| anyhow!("Song {} is not loaded", track.file()))?;
+        let Some(handle) = self.audio.play_looping_sound(song)? else {
+            return Ok(());
+        };
+        handle.set_playback_rate(track.playback_rate());
+        handle.set_volume(0.0);
+        handle.fade_to(track.volume() * volume_scale, MUSIC_CROSSFADE_SECONDS)?;
+        self.current = Some((track, handle));
+        self.volume_scale = volume_scale;
+        Ok(())
+    }
+
+    fn adjust(
+        &mut self,
+        (track, volume_scale): (MusicTrack, f32),
+        volume: f32,
+        playback_rate: f32,
+    ) -> Result<()> {
+        let Some((current, handle)) = &mut self.current else {
+            return Ok(());
+        };
+        handle.set_playback_rate(playback_rate);
+        handle.fade_to(volume, MUSIC_FADE_SECONDS)?;
+        *current = track;
+        self.volume_scale = volume_scale;
+        Ok(())
+    }
+
+    fn play_knock_out_stinger(&self) {
+        let (from_hz, to_hz) = KNOCK_OUT_STINGER_HZ;
+        if let Err(err) = self
+            .audio
+            .play_tone(from_hz, to_hz, KNOCK_OUT_STINGER_SECONDS)
+        {
+            error!("Could not play knock out stinger {:#?}", err);
+        }
+    }
+}
+
+// 出错重试时旧的游戏会被释放, 它的背景音乐也要跟着停下来
+impl Drop for Music {
+    fn drop(&mut self) {
+        if let Some((_track, handle)) = self.current.take() {
+            if let Err(err) = handle.stop() {
+                error!("Could not stop music {:#?}", err);
+            }
+        }
+    }
+}
+
+/// Ghost 按同一个种子上最好成绩的录像重跑一遍, 半透明地画在玩家身后
+struct Ghost {
+    boy: RedHatBoy,
+    input: GhostInput,
+    finished: bool,
+}
+
+impl Ghost {
+    fn for_seed(boy: &RedHatBoy, ghost_runs: &GhostRuns, seed: u64) -> Option<Self> {
+        ghost_runs.best_for(seed).map(|run| Ghost {
+            boy: boy.ghost(),
+            input: GhostInput::new(run.actions.clone()),
+            finished: false,
+        })
+    }
+
+    fn update(&mut self) {
+        match self.input.next_actions() {
+            Some(actions) => {
+                self.boy.perform(actions);
+                self.boy.update();
+            }
+            None => self.finished = true,
+        }
+    }
+
+    fn is_running(&self) -> bool {
+        !self.finished && !self.boy.knocked_out()
+    }
+
+    fn draw(&self, renderer: &dyn Renderer) -> Result<()> {
+        renderer.set_alpha(GHOST_ALPHA);
+        let result = self.boy.draw(renderer);
+        renderer.set_alpha(1.0);
+        result
+    }
+}
+
+/// SeedSource 决定每一局障碍物使用的随机种子:
+/// 默认每局随机, 也可以通过 URL 参数 `?seed=42` 固定, `?seed=daily` 则是当天所有人相同的种子
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SeedSource {
+    Random,
+    Fixed(u64),
+}
+
+impl SeedSource {
+    fn from_query(value: Option<&str>, now: f64) -> Self {
+        match value.map(str::trim) {
+            Some(DAILY_SEED) => {
+                SeedSource::Fixed(daily_seed((now / MILLISECONDS_PER_DAY).floor() as u64))
+            }
+            Some(value) => value
+                .parse()
+                .map(SeedSource::Fixed)
+                .unwrap_or(SeedSource::Random),
+            None => SeedSource::Random,
+        }
+    }
+
+    fn next_seed(&self) -> u64 {
+        match self {
+            SeedSource::Random => thread_rng().gen_range(0..MAX_RANDOM_SEED),
+            SeedSource::Fixed(seed) => *seed,
+        }
+    }
+}
+
+/// daily_seed 用 splitmix64 把天数打散到整个 u64 上,
+/// 这样每天的种子不会和 `?seed=` 里常用的小数字或者随机种子撞上
+fn daily_seed(day: u64) -> u64 {
+    let mut z = day.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
 pub trait Obstacle {
     /// 检查是否有碰撞
-    fn check_intersection(&self, boy: &mut RedHatBoy);
+    fn check_intersection(&self, boy: &mut RedHatBoy) -> Result<()>;
+
+    fn draw(&self, renderer: &dyn Renderer) -> Result<()>;
 
-    fn draw(&self, renderer: &Renderer);
+    /// draw_bounding_boxes 画出碰撞盒, 用来调试碰撞
+    fn draw_bounding_boxes(&self, renderer: &dyn Renderer);
 
     fn move_horizontally(&mut self, x: i16);
 
@@ -419,9 +1512,9 @@ pub struct Platform {
 }
 
 impl Obstacle for Platform {
-    fn draw(&self, renderer: &Renderer) {
+    fn draw(&self, renderer: &dyn Renderer) -> Result<()> {
         let mut x = 0;
-        self.sprites.iter().for_each(|sprite| {
+        self.sprites.iter().try_for_each(|sprite| {
             self.sheet.draw(
                 renderer,
                 &Rect::new_from_x_y(
@@ -437,8 +1530,24 @@ impl Obstacle for Platform {
                     sprite.frame.w,
                     sprite.frame.h,
                 ),
-            );
+            )?;
             x += sprite.frame.w;
+            Ok(())
+        })
+    }
+
+    fn draw_bounding_boxes(&self, renderer: &dyn Renderer) {
+        self.bounding_boxes.iter().for_each(|bounding_box| {
+            renderer.stroke_rect(bounding_box, PLATFORM_BOUNDING_BOX_COLOR);
+            // 只有从上方落到碰撞盒顶边才能站上去, 其余方向的碰撞都会被撞倒
+            renderer.fill_rect(
+                &Rect::new(
+                    bounding_box.position,
+                    bounding_box.width,
+                    LANDING_SURFACE_HEIGHT,
+                ),
+                LANDING_SURFACE_COLOR,
+            );
         });
     }
 
@@ -449,11 +1558,12 @@ impl Obstacle for Platform {
             .for_each(|bounding_box| bounding_box.set_x(bounding_box.position.x + x))
     }
 
-    fn check_intersection(&self, boy: &mut RedHatBoy) {
+    fn check_intersection(&self, boy: &mut RedHatBoy) -> Result<()> {
+        let boy_box = boy.bounding_box()?;
         if let Some(box_to_land_on) = self
             .bounding_boxes()
             .iter()
-            .find(|&bounding_box| boy.bounding_box().intersects(bounding_box))
+            .find(|&bounding_box| boy_box.intersects(bounding_box))
         {
             if boy.velocity_y() > 0 && boy.pos_y() < self.position.y {
                 boy.land_on(box_to_land_on.y());
@@ -461,6 +1571,7 @@ impl Obstacle for Platform {
                 boy.knock_out();
             }
         }
+        Ok(())
     }
 
     fn right(&self) -> i16 {
@@ -519,14 +1630,19 @@ impl Barrier {
 }
 
 impl Obstacle for Barrier {
-    fn check_intersection(&self, boy: &mut RedHatBoy) {
-        if boy.bounding_box().intersects(self.image.bounding_box()) {
+    fn check_intersection(&self, boy: &mut RedHatBoy) -> Result<()> {
+        if boy.bounding_box()?.intersects(self.image.bounding_box()) {
             boy.knock_out()
         }
+        Ok(())
     }
 
-    fn draw(&self, renderer: &Renderer) {
-        self.image.draw(renderer);
+    fn draw(&self, renderer: &dyn Renderer) -> Result<()> {
+        self.image.draw(renderer)
+    }
+
+    fn draw_bounding_boxes(&self, renderer: &dyn Renderer) {
+        renderer.stroke_rect(self.image.bounding_box(), BARRIER_BOUNDING_BOX_COLOR);
     }
 
     fn move_horizontally(&mut self, x: i16) {
@@ -543,18 +1659,40 @@ impl Obstacle for Barrier {
 pub struct RedHatBoy {
     state_machine: RedHatBoyStateMachine,
     sprite_sheet: Sheet,
-    image: HtmlImageElement,
+    image: ImageHandle,
 }
 
 impl RedHatBoy {
-    fn new(sheet: Sheet, image: HtmlImageElement, audio: Audio, sound: Sound) -> Self {
+    fn new(sheet: Sheet, image: ImageHandle, audio: Audio, sounds: SoundTable) -> Self {
         Self {
-            state_machine: RedHatBoyStateMachine::Idle(RedHatBoyState::new(audio, sound)),
+            state_machine: RedHatBoyStateMachine::Idle(RedHatBoyState::new(audio, sounds)),
             sprite_sheet: sheet,
             image,
         }
     }
 
+    /// ghost 返回一个使用同样精灵图的 RHB, 但是不会播放音效
+    fn ghost(&self) -> Self {
+        let context = self.state_machine.context();
+        Self {
+            state_machine: RedHatBoyStateMachine::Idle(
+                RedHatBoyState::new(context.audio.clone(), context.sounds.clone())
+                    .muted()
+                    .with_jump_params(context.jump_params),
+            ),
+            sprite_sheet: self.sprite_sheet.clone(),
+            image: self.image.clone(),
+        }
+    }
+
+    /// with_jump_params 调整跳跃手感, 只在开始跑之前生效
+    pub fn with_jump_params(mut self, params: JumpParams) -> Self {
+        if let RedHatBoyStateMachine::Idle(state) = self.state_machine {
+            self.state_machine = state.with_jump_params(params).into();
+        }
+        self
+    }
+
     fn frame_name(&self) -> String {
         format!(
             "{} ({}).png",
@@ -563,36 +1701,38 @@ impl RedHatBoy {
         )
     }
 
-    fn current_sprite(&self) -> Option<&Cell> {
+    fn current_sprite(&self) -> Result<&Cell> {
         let frame_name = self.frame_name();
-        self.sprite_sheet.frames.get(&frame_name)
+        self.sprite_sheet.frames.get(&frame_name).ok_or_else(|| {
+            EngineError::Asset(format!("Cell {} not found in rhb.json", frame_name)).into()
+        })
     }
 
-    fn bounding_box(&self) -> Rect {
+    fn bounding_box(&self) -> Result<Rect> {
         const X_OFFSET: i16 = 18;
         const Y_OFFSET: i16 = 14;
         const WIDTH_OFFSET: i16 = 28;
-        let mut bounding_box = self.destination_box();
+        let mut bounding_box = self.destination_box()?;
         bounding_box.set_x(X_OFFSET);
         bounding_box.width -= WIDTH_OFFSET;
         bounding_box.height -= Y_OFFSET;
-        bounding_box
+        Ok(bounding_box)
     }
 
-    fn destination_box(&self) -> Rect {
-        let sprite = self.current_sprite().expect("Cell not found");
-        Rect {
+    fn destination_box(&self) -> Result<Rect> {
+        let sprite = self.current_sprite()?;
+        Ok(Rect {
             position: Point {
                 x: (self.state_machine.context().position.x + sprite.sprite_source_size.x),
                 y: (self.state_machine.context().position.y + sprite.sprite_source_size.y),
             },
             width: sprite.frame.w,
             height: sprite.frame.h,
-        }
+        })
     }
 
-    fn draw(&self, renderer: &Renderer) {
-        let sprite = self.current_sprite().expect("Cell not found");
+    fn draw(&self, renderer: &dyn Renderer) -> Result<()> {
+        let sprite = self.current_sprite()?;
 
         renderer.draw_image(
             &self.image,
@@ -604,14 +1744,24 @@ impl RedHatBoy {
                 width: sprite.frame.w,
                 height: sprite.frame.h,
             },
-            &self.destination_box(),
+            &self.destination_box()?,
         )
     }
 
+    fn draw_bounding_boxes(&self, renderer: &dyn Renderer) -> Result<()> {
+        renderer.stroke_rect(&self.destination_box()?, RHB_SPRITE_BOX_COLOR);
+        renderer.stroke_rect(&self.bounding_box()?, RHB_BOUNDING_BOX_COLOR);
+        Ok(())
+    }
+
     fn walking_speed(&self) -> i16 {
         self.state_machine.context().velocity.x
     }
 
+    fn state_name(&self) -> &str {
+        self.state_machine.name()
+    }
+
     fn update(&mut self) {
         self.state_machine = self.state_machine.clone().update();
     }
@@ -628,14 +1778,35 @@ impl RedHatBoy {
         self.state_machine = self.state_machine.clone().transition(Event::Jump)
     }
 
+    fn release_jump(&mut self) {
+        self.state_machine = self.state_machine.clone().transition(Event::ReleaseJump)
+    }
+
     fn knock_out(&mut self) {
         self.state_machine = self.state_machine.clone().transition(Event::KnockOut);
     }
 
+    /// perform 执行一次 update 里收到的动作, 玩家和幽灵走的是同一套逻辑
+    fn perform(&mut self, actions: GhostActions) {
+        if actions.jump {
+            self.jump();
+        }
+        if actions.release_jump {
+            self.release_jump();
+        }
+        if actions.slide {
+            self.slide();
+        }
+    }
+
     fn knocked_out(&self) -> bool {
         self.state_machine.knocked_out()
     }
 
+    fn play_sound(&self, effect: SoundEffect) {
+        self.state_machine.context().play(effect);
+    }
+
     fn pos_y(&self) -> i16 {
         self.state_machine.context().position.y
     }
@@ -653,8 +1824,9 @@ impl RedHatBoy {
             boy.sprite_sheet,
             boy.image,
             boy.state_machine.context().audio.clone(),
-            boy.state_machine.context().jump_sound.clone(),
+            boy.state_machine.context().sounds.clone(),
         )
+        .with_jump_params(boy.state_machine.context().jump_params)
     }
 }
 
@@ -666,6 +1838,7 @@ enum RedHatBoyStateMachine {
     Running(RedHatBoyState<Running>),
     Sliding(RedHatBoyState<Sliding>),
     Jumping(RedHatBoyState<Jumping>),
+    Airborne(RedHatBoyState<Airborne>),
     Falling(RedHatBoyState<Falling>),
     KnockedOut(RedHatBoyState<KnockedOut>),
 }
@@ -675,13 +1848,14 @@ pub enum Event {
     Slide,
     Update,
     Jump,
+    ReleaseJump,
     KnockOut,
     Land(i16),
 }
 
 impl RedHatBoyStateMachine {
     fn transition(self, event: Event) -> Self {
-        match (self.clone(), event) {
+        let next = match (self.clone(), event) {
             // =================================================================
             // to Run
             (RedHatBoyStateMachine::Idle(state), Event::Run) => state.run().into(),
@@ -696,17 +1870,48 @@ impl RedHatBoyStateMachine {
             (RedHatBoyStateMachine::Running(state), Event::Update) => state.update().into(),
             (RedHatBoyStateMachine::Sliding(state), Event::Update) => state.update().into(),
             (RedHatBoyStateMachine::Jumping(state), Event::Update) => state.update().into(),
+            (RedHatBoyStateMachine::Airborne(state), Event::Update) => state.update().into(),
             (RedHatBoyStateMachine::Falling(state), Event::Update) => state.update().into(),
 
             // =================================================================
             // to Jump
-            (RedHatBoyStateMachine::Running(state), Event::Jump) => state.jump().into(),
+            (RedHatBoyStateMachine::Running(state), Event::Jump) => {
+                if state.can_jump() {
+                    state.jump().into()
+                } else {
+                    state.buffer_jump().into()
+                }
+            }
+            (RedHatBoyStateMachine::Airborne(state), Event::Jump) => {
+                if state.can_jump() {
+                    state.jump().into()
+                } else {
+                    state.buffer_jump().into()
+                }
+            }
+            // 还不能跳的时候先记下来, 落地后在 jump_if_buffered 里补上
+            (RedHatBoyStateMachine::Jumping(state), Event::Jump) => state.buffer_jump().into(),
+            (RedHatBoyStateMachine::Sliding(state), Event::Jump) => state.buffer_jump().into(),
+            (RedHatBoyStateMachine::Jumping(state), Event::ReleaseJump) => {
+                state.release_jump().into()
+            }
+            // 缓冲着的跳跃在落地前松开了, 补跳的时候也只是小跳
+            (RedHatBoyStateMachine::Running(state), Event::ReleaseJump) => {
+                state.release_buffered_jump().into()
+            }
+            (RedHatBoyStateMachine::Sliding(state), Event::ReleaseJump) => {
+                state.release_buffered_jump().into()
+            }
+            (RedHatBoyStateMachine::Airborne(state), Event::ReleaseJump) => {
+                state.release_buffered_jump().into()
+            }
 
             // =================================================================
             // to KnockOut
             (RedHatBoyStateMachine::Sliding(state), Event::KnockOut) => state.knock_out().into(),
             (RedHatBoyStateMachine::Jumping(state), Event::KnockOut) => state.knock_out().into(),
             (RedHatBoyStateMachine::Running(state), Event::KnockOut) => state.knock_out().into(),
+            (RedHatBoyStateMachine::Airborne(state), Event::KnockOut) => state.knock_out().into(),
 
             // =================================================================
             // to Land
@@ -719,6 +1924,25 @@ impl RedHatBoyStateMachine {
             (RedHatBoyStateMachine::Sliding(state), Event::Land(position)) => {
                 state.land_on(position).into()
             }
+            (RedHatBoyStateMachine::Airborne(state), Event::Land(position)) => {
+                state.land_on(position).into()
+            }
+            _ => self,
+        };
+        next.jump_if_buffered()
+    }
+
+    fn jump_if_buffered(self) -> Self {
+        match self {
+            RedHatBoyStateMachine::Running(state)
+                if state.can_jump() && state.context().jump_timing.jump_buffered() =>
+            {
+                if state.context().jump_timing.jump_released() {
+                    state.jump().release_jump().into()
+                } else {
+                    state.jump().into()
+                }
+            }
             _ => self,
         }
     }
@@ -729,17 +1953,31 @@ impl RedHatBoyStateMachine {
             RedHatBoyStateMachine::Running(state) => state.frame_name(),
             RedHatBoyStateMachine::Sliding(state) => state.frame_name(),
             RedHatBoyStateMachine::Jumping(state) => state.frame_name(),
+            RedHatBoyStateMachine::Airborne(state) => state.frame_name(),
             RedHatBoyStateMachine::Falling(state) => state.frame_name(),
             RedHatBoyStateMachine::KnockedOut(state) => state.frame_name(),
         }
     }
 
+    fn name(&self) -> &str {
+        match self {
+            RedHatBoyStateMachine::Idle(_) => "Idle",
+            RedHatBoyStateMachine::Running(_) => "Running",
+            RedHatBoyStateMachine::Sliding(_) => "Sliding",
+            RedHatBoyStateMachine::Jumping(_) => "Jumping",
+            RedHatBoyStateMachine::Airborne(_) => "Airborne",
+            RedHatBoyStateMachine::Falling(_) => "Falling",
+            RedHatBoyStateMachine::KnockedOut(_) => "KnockedOut",
+        }
+    }
+
     fn context(&self) -> &RedHatBoyContext {
         match self {
             RedHatBoyStateMachine::Idle(state) => state.context(),
             RedHatBoyStateMachine::Running(state) => state.context(),
             RedHatBoyStateMachine::Sliding(state) => state.context(),
             RedHatBoyStateMachine::Jumping(state) => state.context(),
+            RedHatBoyStateMachine::Airborne(state) => state.context(),
             RedHatBoyStateMachine::Falling(state) => state.context(),
             RedHatBoyStateMachine::KnockedOut(state) => state.context(),
         }
@@ -778,6 +2016,12 @@ impl From<RedHatBoyState<Jumping>> for RedHatBoyStateMachine {
     }
 }
 
+impl From<RedHatBoyState<Airborne>> for RedHatBoyStateMachine {
+    fn from(state: RedHatBoyState<Airborne>) -> Self {
+        RedHatBoyStateMachine::Airborne(state)
+    }
+}
+
 impl From<RedHatBoyState<Falling>> for RedHatBoyStateMachine {
     fn from(state: RedHatBoyState<Falling>) -> Self {
         RedHatBoyStateMachine::Falling(state)
@@ -795,6 +2039,16 @@ impl From<SlidingEndState> for RedHatBoyStateMachine {
         match end_state {
             SlidingEndState::Running(running) => running.into(),
             SlidingEndState::Sliding(sliding) => sliding.into(),
+            SlidingEndState::Airborne(airborne) => airborne.into(),
+        }
+    }
+}
+
+impl From<RunningEndState> for RedHatBoyStateMachine {
+    fn from(end_state: RunningEndState) -> Self {
+        match end_state {
+            RunningEndState::Running(running) => running.into(),
+            RunningEndState::Airborne(airborne) => airborne.into(),
         }
     }
 }
@@ -808,6 +2062,15 @@ impl From<JumpingEndState> for RedHatBoyStateMachine {
     }
 }
 
+impl From<AirborneEndState> for RedHatBoyStateMachine {
+    fn from(end_state: AirborneEndState) -> Self {
+        match end_state {
+            AirborneEndState::Airborne(airborne) => airborne.into(),
+            AirborneEndState::Landing(running) => running.into(),
+        }
+    }
+}
+
 impl From<FallingEndState> for RedHatBoyStateMachine {
     fn from(state: FallingEndState) -> Self {
         match state {
@@ -817,19 +2080,25 @@ impl From<FallingEndState> for RedHatBoyStateMachine {
     }
 }
 
-fn right_most(obstacle_list: &[Box<dyn Obstacle>]) -> i16 {
+fn starting_obstacles(segments: &SegmentSheet, assets: &SegmentAssets) -> Vec<Box<dyn Obstacle>> {
+    segments
+        .starting_segment()
+        .map(|segment| create_segment(segment, assets, 0))
+        .unwrap_or_default()
+}
+
+fn right_most(obstacle_list: &[Box<dyn Obstacle>]) -> Option<i16> {
     obstacle_list
         .iter()
         .map(|obstacle| obstacle.right())
         // the max_by function to figure out the maximum value on the right
         .max_by(|x, y| x.cmp(y))
-        .unwrap_or(0)
 }
 
 // =============================================================================
 // redharboy states submodules
 mod red_hat_boy_states {
-    use std::marker;
+    use std::{collections::HashMap, marker, rc::Rc};
 
     use crate::engine::{Audio, Point, Sound};
 
@@ -844,6 +2113,8 @@ mod red_hat_boy_states {
     const RUN_FRAME_NAME: &str = "Run";
     const RUN_FRAMES: u8 = 23;
     const RUNNING_SPEED: i16 = 3;
+    // Run 动画里两只脚落地的那两帧(每张图片显示 3 次 update)
+    const FOOTSTEP_FRAMES: [u8; 2] = [6, 18];
 
     const SLIDING_FRAME_NAME: &str = "Slide";
     const SLIDING_FRAMES: u8 = 14;
@@ -851,21 +2122,59 @@ mod red_hat_boy_states {
     const JUMPING_FRAME_NAME: &str = "Jump";
     const JUMPING_FRAMES: u8 = 35;
     const JUMP_SPEED: i16 = -25;
+    const JUMP_CUT_SPEED: i16 = -8;
 
     const FALLING_FRAMES: u8 = 29; // 10 'Dead' frames in the sheet, * 3 - 1
     const FALLING_FRAME_NAME: &str = "Dead";
+    const JUMP_BUFFER_FRAMES: u8 = 6;
+    const COYOTE_FRAMES: u8 = 6;
 
     const GRAVITY: i16 = 1;
     const PLAYER_HEIGHT: i16 = HEIGHT - FLOOR;
     const TERMINAL_VELOCITY: i16 = 20;
 
+    /// SoundEffect 是 RHB 用到的所有音效, 包括界面按钮的点击声
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub enum SoundEffect {
+        Jump,
+        Slide,
+        Land,
+        KnockOut,
+        Footstep,
+        UiClick,
+    }
+
+    impl SoundEffect {
+        pub const ALL: [SoundEffect; 6] = [
+            SoundEffect::Jump,
+            SoundEffect::Slide,
+            SoundEffect::Land,
+            SoundEffect::KnockOut,
+            SoundEffect::Footstep,
+            SoundEffect::UiClick,
+        ];
+
+        pub fn file(&self) -> &'static str {
+            match self {
+                SoundEffect::Jump => "SFX_Jump_23.mp3",
+                SoundEffect::Slide => "SFX_Slide.wav",
+                SoundEffect::Land => "SFX_Land.wav",
+                SoundEffect::KnockOut => "SFX_KnockOut.wav",
+                SoundEffect::Footstep => "SFX_Footstep.wav",
+                SoundEffect::UiClick => "SFX_Click.wav",
+            }
+        }
+    }
+
+    pub type SoundTable = Rc<HashMap<SoundEffect, Sound>>;
+
     // =========================================================================
     // Idle
     #[derive(Clone, Copy)]
     pub struct Idle;
 
     impl RedHatBoyState<Idle> {
-        pub fn new(audio: Audio, jump_sound: Sound) -> Self {
+        pub fn new(audio: Audio, sounds: SoundTable) -> Self {
             RedHatBoyState {
                 ctx: RedHatBoyContext {
                     frame: 0,
@@ -874,13 +2183,27 @@ mod red_hat_boy_states {
                         y: FLOOR,
                     },
                     velocity: Point { x: 0, y: 0 },
+                    jump_timing: JumpTiming::default(),
+                    jump_params: JumpParams::default(),
                     audio,
-                    jump_sound,
+                    sounds,
+                    muted: false,
                 },
                 _state: marker::PhantomData,
             }
         }
 
+        /// muted 的 RHB 不会播放音效, 用于幽灵
+        pub fn muted(mut self) -> Self {
+            self.ctx.muted = true;
+            self
+        }
+
+        pub fn with_jump_params(mut self, params: JumpParams) -> Self {
+            self.ctx.jump_params = params;
+            self
+        }
+
         pub fn run(self) -> RedHatBoyState<Running> {
             RedHatBoyState {
                 ctx: self.ctx.reset_frame().run_right(),
@@ -903,6 +2226,7 @@ mod red_hat_boy_states {
     pub enum SlidingEndState {
         Running(RedHatBoyState<Running>),
         Sliding(RedHatBoyState<Sliding>),
+        Airborne(RedHatBoyState<Airborne>),
     }
 
     #[derive(Clone, Copy)]
@@ -914,6 +2238,9 @@ mod red_hat_boy_states {
         }
 
         pub fn update(mut self) -> SlidingEndState {
+            if !self.ctx.jump_timing.on_ground() {
+                return SlidingEndState::Airborne(self.fall_off());
+            }
             self.update_context(SLIDING_FRAMES);
             if self.ctx.frame >= SLIDING_FRAMES {
                 SlidingEndState::Running(self.stand())
@@ -931,7 +2258,11 @@ mod red_hat_boy_states {
 
         pub fn knock_out(self) -> RedHatBoyState<Falling> {
             RedHatBoyState {
-                ctx: self.ctx.reset_frame().stop(),
+                ctx: self
+                    .ctx
+                    .reset_frame()
+                    .stop()
+                    .play_sound(SoundEffect::KnockOut),
                 _state: marker::PhantomData,
             }
         }
@@ -946,6 +2277,11 @@ mod red_hat_boy_states {
 
     // =========================================================================
     // Running
+    pub enum RunningEndState {
+        Running(RedHatBoyState<Running>),
+        Airborne(RedHatBoyState<Airborne>),
+    }
+
     #[derive(Clone, Copy)]
     pub struct Running;
 
@@ -954,32 +2290,50 @@ mod red_hat_boy_states {
             RUN_FRAME_NAME
         }
 
-        pub fn update(mut self) -> Self {
+        pub fn update(mut self) -> RunningEndState {
+            if !self.ctx.jump_timing.on_ground() {
+                return RunningEndState::Airborne(self.fall_off());
+            }
             self.update_context(RUN_FRAMES);
-            self
+            if FOOTSTEP_FRAMES.contains(&self.ctx.frame) {
+                self.ctx.play(SoundEffect::Footstep);
+            }
+            RunningEndState::Running(self)
         }
 
         pub fn slide(self) -> RedHatBoyState<Sliding> {
             RedHatBoyState {
-                ctx: self.ctx.reset_frame(),
+                ctx: self.ctx.reset_frame().play_sound(SoundEffect::Slide),
                 _state: marker::PhantomData,
             }
         }
 
-        pub fn jump(self) -> RedHatBoyState<Jumping> {
+        pub fn can_jump(&self) -> bool {
+            self.ctx
+                .jump_timing
+                .can_jump(self.ctx.jump_params.coyote_frames)
+        }
+
+        pub fn jump(mut self) -> RedHatBoyState<Jumping> {
+            self.ctx.jump_timing.jumped();
+            let jump_speed = self.ctx.jump_params.jump_speed;
             RedHatBoyState {
                 ctx: self
                     .ctx
                     .reset_frame()
-                    .set_vertical_velocity(JUMP_SPEED)
-                    .play_jump_sound(),
+                    .set_vertical_velocity(jump_speed)
+                    .play_sound(SoundEffect::Jump),
                 _state: marker::PhantomData,
             }
         }
 
         pub fn knock_out(self) -> RedHatBoyState<Falling> {
             RedHatBoyState {
-                ctx: self.ctx.reset_frame().stop(),
+                ctx: self
+                    .ctx
+                    .reset_frame()
+                    .stop()
+                    .play_sound(SoundEffect::KnockOut),
                 _state: marker::PhantomData,
             }
         }
@@ -1007,6 +2361,14 @@ mod red_hat_boy_states {
             JUMPING_FRAME_NAME
         }
 
+        pub fn release_jump(mut self) -> Self {
+            self.ctx.jump_timing.release_jump();
+            RedHatBoyState {
+                ctx: self.ctx.cut_jump(),
+                _state: marker::PhantomData,
+            }
+        }
+
         pub fn update(mut self) -> JumpingEndState {
             self.update_context(JUMPING_FRAMES);
             if self.ctx.position.y >= FLOOR {
@@ -1018,14 +2380,89 @@ mod red_hat_boy_states {
 
         pub fn knock_out(self) -> RedHatBoyState<Falling> {
             RedHatBoyState {
-                ctx: self.ctx.reset_frame().stop(),
+                ctx: self
+                    .ctx
+                    .reset_frame()
+                    .stop()
+                    .play_sound(SoundEffect::KnockOut),
+                _state: marker::PhantomData,
+            }
+        }
+
+        pub fn land_on(self, position: i16) -> RedHatBoyState<Running> {
+            RedHatBoyState {
+                ctx: self
+                    .ctx
+                    .reset_frame()
+                    .set_on(position)
+                    .play_sound(SoundEffect::Land),
+                _state: marker::PhantomData,
+            }
+        }
+    }
+
+    // =========================================================================
+    // Airborne
+    // 从平台边缘走下去之后在空中下落, 和被撞倒之后的 Falling 不同, 落地后继续跑
+    pub enum AirborneEndState {
+        Landing(RedHatBoyState<Running>),
+        Airborne(RedHatBoyState<Airborne>),
+    }
+
+    #[derive(Clone, Copy)]
+    pub struct Airborne;
+
+    impl RedHatBoyState<Airborne> {
+        pub fn frame_name(&self) -> &str {
+            JUMPING_FRAME_NAME
+        }
+
+        pub fn can_jump(&self) -> bool {
+            self.ctx
+                .jump_timing
+                .can_jump(self.ctx.jump_params.coyote_frames)
+        }
+
+        pub fn jump(mut self) -> RedHatBoyState<Jumping> {
+            self.ctx.jump_timing.jumped();
+            let jump_speed = self.ctx.jump_params.jump_speed;
+            RedHatBoyState {
+                ctx: self
+                    .ctx
+                    .reset_frame()
+                    .set_vertical_velocity(jump_speed)
+                    .play_sound(SoundEffect::Jump),
+                _state: marker::PhantomData,
+            }
+        }
+
+        pub fn update(mut self) -> AirborneEndState {
+            self.update_context(JUMPING_FRAMES);
+            if self.ctx.position.y >= FLOOR {
+                AirborneEndState::Landing(self.land_on(HEIGHT))
+            } else {
+                AirborneEndState::Airborne(self)
+            }
+        }
+
+        pub fn knock_out(self) -> RedHatBoyState<Falling> {
+            RedHatBoyState {
+                ctx: self
+                    .ctx
+                    .reset_frame()
+                    .stop()
+                    .play_sound(SoundEffect::KnockOut),
                 _state: marker::PhantomData,
             }
         }
 
         pub fn land_on(self, position: i16) -> RedHatBoyState<Running> {
             RedHatBoyState {
-                ctx: self.ctx.reset_frame().set_on(position),
+                ctx: self
+                    .ctx
+                    .reset_frame()
+                    .set_on(position)
+                    .play_sound(SoundEffect::Land),
                 _state: marker::PhantomData,
             }
         }
@@ -1094,6 +2531,114 @@ mod red_hat_boy_states {
         fn update_context(&mut self, frames: u8) {
             self.ctx = self.ctx.clone().update(frames)
         }
+
+        pub fn buffer_jump(mut self) -> Self {
+            self.ctx
+                .jump_timing
+                .buffer_jump(self.ctx.jump_params.buffer_frames);
+            self
+        }
+
+        pub fn release_buffered_jump(mut self) -> Self {
+            self.ctx.jump_timing.release_jump();
+            self
+        }
+
+        /// fall_off 在脚下没有平台也不在地面上时进入 Airborne, 这一次 update 就开始下落
+        fn fall_off(self) -> RedHatBoyState<Airborne> {
+            let mut airborne = RedHatBoyState {
+                ctx: self.ctx.reset_frame(),
+                _state: marker::PhantomData,
+            };
+            airborne.update_context(JUMPING_FRAMES);
+            airborne
+        }
+    }
+
+    /// JumpTiming 记录跳跃缓冲和离地时间, 让起跳的时机不用那么精确
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct JumpTiming {
+        buffered: u8,
+        released: bool,
+        airborne: u8,
+    }
+
+    impl JumpTiming {
+        pub fn buffer_jump(&mut self, frames: u8) {
+            self.buffered = frames;
+            self.released = false;
+        }
+
+        pub fn jump_buffered(&self) -> bool {
+            self.buffered > 0
+        }
+
+        /// release_jump 记下缓冲着的跳跃在起跳之前就松开了
+        pub fn release_jump(&mut self) {
+            self.released = self.jump_buffered();
+        }
+
+        pub fn jump_released(&self) -> bool {
+            self.released
+        }
+
+        pub fn can_jump(&self, coyote_frames: u8) -> bool {
+            self.airborne <= coyote_frames
+        }
+
+        /// on_ground 表示上一次 update 之后落在了地面或者平台上
+        pub fn on_ground(&self) -> bool {
+            self.airborne == 0
+        }
+
+        pub fn jumped(&mut self) {
+            self.buffered = 0;
+            self.released = false;
+        }
+
+        pub fn land(&mut self) {
+            self.airborne = 0;
+        }
+
+        pub fn update(&mut self, grounded: bool) {
+            self.buffered = self.buffered.saturating_sub(1);
+            self.airborne = if grounded {
+                0
+            } else {
+                self.airborne.saturating_add(1)
+            };
+        }
+    }
+
+    /// JumpParams 是可以调整的跳跃手感, 默认值就是上面的 JUMP_* 常量
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct JumpParams {
+        /// 起跳时的垂直速度, 按住跳跃键不放时跳得最高
+        pub jump_speed: i16,
+        /// 提前松开跳跃键时, 上升速度最多保留到 cut_speed, 数值越接近 0 小跳越矮
+        pub cut_speed: i16,
+        /// 提前按下的跳跃在这么多次 update 之内落地仍然有效
+        pub buffer_frames: u8,
+        /// 离开地面之后的这么多次 update 之内仍然可以起跳(coyote time)
+        pub coyote_frames: u8,
+    }
+
+    impl Default for JumpParams {
+        fn default() -> Self {
+            JumpParams {
+                jump_speed: JUMP_SPEED,
+                cut_speed: JUMP_CUT_SPEED,
+                buffer_frames: JUMP_BUFFER_FRAMES,
+                coyote_frames: COYOTE_FRAMES,
+            }
+        }
+    }
+
+    impl JumpParams {
+        /// cut_velocity 只截断上升的速度, 已经在下落时不受影响
+        pub fn cut_velocity(&self, velocity_y: i16) -> i16 {
+            velocity_y.max(self.cut_speed)
+        }
     }
 
     #[derive(Clone)]
@@ -1101,8 +2646,11 @@ mod red_hat_boy_states {
         pub frame: u8,
         pub position: Point,
         pub velocity: Point,
+        pub jump_timing: JumpTiming,
+        pub jump_params: JumpParams,
         pub audio: Audio,
-        pub jump_sound: Sound,
+        pub sounds: SoundTable,
+        pub muted: bool,
     }
 
     impl RedHatBoyContext {
@@ -1122,6 +2670,7 @@ mod red_hat_boy_states {
             if self.position.y > FLOOR {
                 self.position.y = FLOOR;
             }
+            self.jump_timing.update(self.position.y >= FLOOR);
 
             self
         }
@@ -1141,6 +2690,11 @@ mod red_hat_boy_states {
             self
         }
 
+        fn cut_jump(mut self) -> Self {
+            self.velocity.y = self.jump_params.cut_velocity(self.velocity.y);
+            self
+        }
+
         fn stop(mut self) -> Self {
             self.velocity.x = 0;
             self.velocity.y = 0;
@@ -1150,21 +2704,33 @@ mod red_hat_boy_states {
         fn set_on(mut self, position: i16) -> Self {
             let position = position - PLAYER_HEIGHT;
             self.position.y = position;
+            self.velocity.y = 0;
+            self.jump_timing.land();
             self
         }
 
-        fn play_jump_sound(self) -> Self {
-            if let Err(err) = self.audio.play_sound(&self.jump_sound) {
-                log!("Error playing jump sound {:#?}", err)
-            }
+        fn play_sound(self, effect: SoundEffect) -> Self {
+            self.play(effect);
             self
         }
+
+        pub fn play(&self, effect: SoundEffect) {
+            if self.muted {
+                return;
+            }
+            if let Some(sound) = self.sounds.get(&effect) {
+                if let Err(err) = self.audio.play_sound(sound) {
+                    log!("Error playing {:?} sound {:#?}", effect, err)
+                }
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::engine::{DrawCommand, GameLoop, RecordingRenderer};
     use futures::channel::mpsc::unbounded;
     use std::collections::HashMap;
     use web_sys::{AudioBuffer, AudioBufferOptions};
@@ -1176,7 +2742,7 @@ mod tests {
     #[wasm_bindgen_test]
     fn test_transition_from_game_over_to_new_game() {
         let (_, receiver) = unbounded();
-        let image = HtmlImageElement::new().unwrap();
+        let image = ImageHandle::headless("rhb.png", 0, 0);
         let audio = Audio::new().unwrap();
         let options = AudioBufferOptions::new(1, 3000.0);
         let sound = Sound {
@@ -1187,8 +2753,8 @@ mod tests {
                 frames: HashMap::new(),
             },
             image.clone(),
-            audio,
-            sound,
+            audio.clone(),
+            Rc::new(HashMap::from([(SoundEffect::Jump, sound)])),
         );
         let sprite_sheet = SpriteSheet::new(
             Sheet {
@@ -1203,9 +2769,30 @@ mod tests {
                 Image::new(image.clone(), Point { x: 0, y: 0 }),
             ],
             obstacles: vec![],
-            obstacle_sheet: Rc::new(sprite_sheet),
-            stone: image,
+            segments: SegmentSheet {
+                starting_segment: "empty".into(),
+                segments: vec![],
+            },
+            segment_assets: SegmentAssets {
+                sprite_sheet: Rc::new(sprite_sheet),
+                images: HashMap::new(),
+            },
             timeline: 0,
+            scrolled: 0,
+            distance: 0,
+            show_bounding_boxes: false,
+            bindings: InputBindings::default(),
+            high_scores: HighScoreTable::default(),
+            storage: Rc::new(MemoryStorage::new()),
+            audio,
+            player_bindings: None,
+            ghost_runs: GhostRuns::default(),
+            ghost_recorder: GhostRecorder::default(),
+            ghost: None,
+            show_ghost: true,
+            seed_source: SeedSource::Fixed(0),
+            seed: 0,
+            rng: StdRng::seed_from_u64(0),
         };
 
         let document = browser::document().unwrap();
@@ -1227,4 +2814,544 @@ mod tests {
         let ui = browser::find_html_element_by_id("ui").unwrap();
         assert_eq!(ui.child_element_count(), 0);
     }
+
+    fn headless_assets() -> WalkAssets {
+        let tiles: Sheet = serde_json::from_str(include_str!("../static/tiles.json")).unwrap();
+        WalkAssets {
+            rhb_sheet: serde_json::from_str(include_str!("../static/rhb.json")).unwrap(),
+            rhb_image: ImageHandle::headless("rhb.png", 1989, 366),
+            sounds: Rc::new(HashMap::new()),
+            background: ImageHandle::headless("BG.png", 1000, 750),
+            segments: serde_json::from_str(include_str!("../static/segments.json")).unwrap(),
+            segment_assets: SegmentAssets {
+                sprite_sheet: Rc::new(SpriteSheet::new(
+                    tiles,
+                    ImageHandle::headless("tiles.png", 650, 485),
+                )),
+                images: HashMap::from([(
+                    "Stone.png".to_string(),
+                    ImageHandle::headless("Stone.png", 90, 54),
+                )]),
+            },
+        }
+    }
+
+    fn headless_walk(storage: Rc<dyn Storage>, seed: u64) -> Walk {
+        Walk::new(
+            headless_assets(),
+            Audio::headless(),
+            storage,
+            SeedSource::Fixed(seed),
+        )
+    }
+
+    fn machine(game: &WalkTheDog) -> &WalkTheDogStateMachine {
+        game.machine.as_ref().unwrap()
+    }
+
+    #[test]
+    fn walk_the_dog_runs_natively_through_the_game_loop() {
+        let mut game = WalkTheDog::with_walk(headless_walk(Rc::new(MemoryStorage::new()), 7), None);
+        let mut game_loop = GameLoop::new(0.0);
+        let mut keystate = KeyState::new();
+        let renderer = RecordingRenderer::new();
+
+        keystate.tap("ArrowRight", 1);
+        let updates: u32 = (1..=10)
+            .map(|frame| game_loop.tick(&mut game, f64::from(frame) * 17.0, &mut keystate))
+            .sum();
+        game_loop.draw(&game, &renderer);
+
+        assert_eq!(updates, 10);
+        assert!(game_loop.failure().is_none());
+        assert_eq!(machine(&game).name(), "Walking");
+        let commands = renderer.take_commands();
+        assert!(commands.contains(&DrawCommand::Image {
+            image: "rhb.png".into(),
+            frame: Rect::new_from_x_y(1053, 244, 71, 113),
+            destination: Rect::new_from_x_y(7, 487, 71, 113),
+        }));
+        let texts: Vec<&str> = commands
+            .iter()
+            .filter_map(|command| match command {
+                DrawCommand::Text { text, .. } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(texts, ["Score 2", "Seed 7"]);
+    }
+
+    #[test]
+    fn replays_use_the_recorded_bindings_and_save_nothing() {
+        let storage: Rc<dyn Storage> = Rc::new(MemoryStorage::new());
+        let mut game = WalkTheDog::with_walk(headless_walk(storage.clone(), 7), None);
+        let walk = game.machine.as_mut().unwrap().walk_mut();
+        walk.bindings.bind(Action::Jump, "KeyW").unwrap();
+        let mut game_loop = GameLoop::new(0.0);
+        game_loop.enable_recording();
+        let mut keystate = KeyState::new();
+
+        keystate.tap("ArrowRight", 1);
+        for frame in 1..=12 {
+            if frame == 4 {
+                keystate.set_pressed("KeyW");
+            }
+            game_loop.tick(&mut game, f64::from(frame) * 17.0, &mut keystate);
+        }
+        let snapshot = |game: &WalkTheDog| {
+            let walk = machine(game).walk();
+            (
+                walk.boy.state_name().to_string(),
+                walk.boy.pos_y(),
+                walk.distance,
+            )
+        };
+        let recorded = snapshot(&game);
+        assert_eq!(recorded.0, "Jumping");
+
+        // 录完之后玩家把跳跃改回了空格, 回放仍然按录像里的 KeyW 跳
+        game.machine.as_mut().unwrap().walk_mut().bindings = InputBindings::default();
+        game_loop
+            .start_replay(&mut game, game_loop.replay().unwrap())
+            .unwrap();
+        let walk = machine(&game).walk();
+        assert!(walk.is_replaying());
+        assert_eq!(walk.bindings.keys(Action::Jump), ["KeyW"]);
+        let mut frame = 12;
+        while game_loop.is_replaying() {
+            frame += 1;
+            game_loop.tick(&mut game, f64::from(frame) * 17.0, &mut keystate);
+        }
+
+        assert_eq!(snapshot(&game), recorded);
+        assert_eq!(machine(&game).walk().bindings, InputBindings::default());
+
+        let mut walk = headless_walk(storage.clone(), 7);
+        walk.distance = 1000;
+        walk.start_replay(InputBindings::default());
+        assert_eq!(walk.record_run(), None);
+        assert!(HighScoreTable::load(storage.as_ref())
+            .unwrap()
+            .best()
+            .is_none());
+        walk.stop_replay();
+        assert_eq!(walk.record_run(), Some(0));
+    }
+
+    #[test]
+    fn holding_pause_pauses_once_and_a_fresh_press_resumes() {
+        let mut game = WalkTheDog::with_walk(headless_walk(Rc::new(MemoryStorage::new()), 7), None);
+        let mut keystate = KeyState::new();
+        let mut update = |keystate: &mut KeyState| {
+            game.update(keystate).unwrap();
+            keystate.end_update();
+            machine(&game).name().to_string()
+        };
+        keystate.tap("ArrowRight", 1);
+        assert_eq!(update(&mut keystate), "Walking");
+
+        keystate.set_pressed("Escape");
+        assert_eq!(update(&mut keystate), "Paused");
+        // 按住暂停键不会在暂停和继续之间来回切换
+        assert_eq!(update(&mut keystate), "Paused");
+        keystate.set_released("Escape");
+        assert_eq!(update(&mut keystate), "Paused");
+
+        keystate.set_pressed("KeyP");
+        assert_eq!(update(&mut keystate), "Walking");
+    }
+
+    #[test]
+    fn score_counts_the_distance_walked_and_is_drawn_on_the_hud() {
+        let mut game = WalkTheDog::with_walk(headless_walk(Rc::new(MemoryStorage::new()), 7), None);
+        let mut keystate = KeyState::new();
+        let update = |game: &mut WalkTheDog, keystate: &mut KeyState| {
+            game.update(keystate).unwrap();
+            keystate.end_update();
+            machine(game).walk().distance
+        };
+        assert_eq!(update(&mut game, &mut keystate), 0);
+
+        // Ready 状态里还没开始跑, 开始跑之后每次 update 走 RUNNING_SPEED
+        keystate.tap("ArrowRight", 1);
+        assert_eq!(update(&mut game, &mut keystate), 0);
+        let distance = (0..7).map(|_| update(&mut game, &mut keystate)).last();
+        assert_eq!(distance, Some(21));
+        assert_eq!(machine(&game).walk().score(), 2);
+
+        // 暂停的时候不计分
+        keystate.set_pressed("Escape");
+        assert_eq!(update(&mut game, &mut keystate), 21);
+        assert_eq!(update(&mut game, &mut keystate), 21);
+        assert_eq!(machine(&game).name(), "Paused");
+
+        let renderer = RecordingRenderer::new();
+        game.draw(&renderer, 1.0).unwrap();
+        assert!(renderer.take_commands().contains(&DrawCommand::Text {
+            text: "Score 2".into(),
+            location: Point { x: 10, y: 30 },
+            font: HUD_FONT.into(),
+        }));
+    }
+
+    #[test]
+    fn bounding_box_key_draws_every_collidable_in_its_own_colour() {
+        let mut game = WalkTheDog::with_walk(headless_walk(Rc::new(MemoryStorage::new()), 7), None);
+        let mut keystate = KeyState::new();
+        let colours = |game: &WalkTheDog| {
+            let renderer = RecordingRenderer::new();
+            game.draw(&renderer, 1.0).unwrap();
+            renderer
+                .take_commands()
+                .into_iter()
+                .filter_map(|command| match command {
+                    DrawCommand::StrokeRect { rect, color } => Some((color, rect)),
+                    DrawCommand::FillRect { rect, color } if color == LANDING_SURFACE_COLOR => {
+                        Some((color, rect))
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        assert!(colours(&game).is_empty());
+
+        keystate.tap(BOUNDING_BOX_KEY, 1);
+        game.update(&keystate).unwrap();
+        keystate.end_update();
+        let boxes = colours(&game);
+        let rhb = machine(&game).walk().boy.bounding_box().unwrap();
+        assert!(boxes.contains(&(RHB_BOUNDING_BOX_COLOR.to_string(), rhb)));
+        for colour in [
+            RHB_SPRITE_BOX_COLOR,
+            BARRIER_BOUNDING_BOX_COLOR,
+            PLATFORM_BOUNDING_BOX_COLOR,
+            LANDING_SURFACE_COLOR,
+        ] {
+            assert!(boxes.iter().any(|(color, _)| color == colour), "{}", colour);
+        }
+
+        keystate.tap(BOUNDING_BOX_KEY, 1);
+        game.update(&keystate).unwrap();
+        assert!(colours(&game).is_empty());
+    }
+
+    #[test]
+    fn walking_moves_obstacles_once_and_draws_between_the_last_two_updates() {
+        let mut game = WalkTheDog::with_walk(headless_walk(Rc::new(MemoryStorage::new()), 7), None);
+        let mut keystate = KeyState::new();
+        keystate.tap("ArrowRight", 1);
+        game.update(&keystate).unwrap();
+        keystate.end_update();
+        game.update(&keystate).unwrap();
+
+        let stone_x = |alpha| {
+            let renderer = RecordingRenderer::new();
+            game.draw(&renderer, alpha).unwrap();
+            renderer
+                .take_commands()
+                .into_iter()
+                .find_map(|command| match command {
+                    DrawCommand::EntireImage { image, position } if image == "Stone.png" => {
+                        Some(position.x)
+                    }
+                    _ => None,
+                })
+                .unwrap()
+        };
+        assert_eq!(stone_x(0.0), 150);
+        assert_eq!(stone_x(0.5), 149);
+        assert_eq!(stone_x(1.0), 147);
+    }
+
+    #[test]
+    fn seed_source_reads_fixed_and_daily_seeds_from_query() {
+        let second_day = MILLISECONDS_PER_DAY * 1.5;
+
+        assert_eq!(
+            SeedSource::from_query(Some("42"), second_day),
+            SeedSource::Fixed(42)
+        );
+        assert_eq!(
+            SeedSource::from_query(Some("daily"), second_day),
+            SeedSource::Fixed(daily_seed(1))
+        );
+        assert_ne!(daily_seed(1), daily_seed(2));
+        assert!((0..1000).all(|day| daily_seed(day) >= MAX_RANDOM_SEED));
+        assert_eq!(
+            SeedSource::from_query(Some("not a seed"), second_day),
+            SeedSource::Random
+        );
+        assert_eq!(SeedSource::from_query(None, second_day), SeedSource::Random);
+        assert_eq!(SeedSource::Fixed(42).next_seed(), 42);
+    }
+
+    #[test]
+    fn releasing_jump_early_only_cuts_upward_velocity() {
+        let params = JumpParams::default();
+
+        assert_eq!(params.cut_velocity(-25), -8);
+        assert_eq!(params.cut_velocity(-5), -5);
+        assert_eq!(params.cut_velocity(3), 3);
+    }
+
+    fn running_boy(params: JumpParams) -> RedHatBoy {
+        let assets = headless_assets();
+        let mut boy = RedHatBoy::new(
+            assets.rhb_sheet,
+            assets.rhb_image,
+            Audio::headless(),
+            assets.sounds,
+        )
+        .with_jump_params(params);
+        boy.run_right();
+        boy
+    }
+
+    /// jump_height 起跳后在第 release_after 次 update 之前松开跳跃键, 返回跳起的最大高度
+    fn jump_height(mut boy: RedHatBoy, release_after: usize) -> i16 {
+        let ground = boy.pos_y();
+        let mut highest = ground;
+        for update in 0..100 {
+            if update == release_after {
+                boy.release_jump();
+            }
+            boy.update();
+            highest = highest.min(boy.pos_y());
+            if boy.state_name() == "Running" {
+                break;
+            }
+        }
+        assert_eq!(boy.state_name(), "Running");
+        assert_eq!(boy.pos_y(), ground);
+        ground - highest
+    }
+
+    #[test]
+    fn how_long_jump_is_held_decides_the_jump_height() {
+        let mut boy = running_boy(JumpParams::default());
+        boy.jump();
+        assert_eq!(boy.state_name(), "Jumping");
+        assert_eq!(boy.velocity_y(), -25);
+
+        let full = jump_height(boy, usize::MAX);
+        let mut tapped = running_boy(JumpParams::default());
+        tapped.jump();
+        let tapped = jump_height(tapped, 0);
+        let mut held = running_boy(JumpParams::default());
+        held.jump();
+        let held = jump_height(held, 10);
+        assert!(tapped < held && held < full);
+
+        // 松开跳跃键时已经在下落, 不会影响跳跃高度
+        let mut late = running_boy(JumpParams::default());
+        late.jump();
+        assert_eq!(jump_height(late, 30), full);
+
+        let mut floaty = running_boy(JumpParams {
+            jump_speed: -15,
+            cut_speed: -2,
+            ..JumpParams::default()
+        });
+        floaty.jump();
+        assert_eq!(floaty.velocity_y(), -15);
+        floaty.release_jump();
+        assert_eq!(floaty.velocity_y(), -2);
+    }
+
+    #[test]
+    fn jump_timing_buffers_early_presses_and_allows_coyote_jumps() {
+        use red_hat_boy_states::JumpTiming;
+
+        let mut timing = JumpTiming::default();
+        timing.buffer_jump(6);
+        (0..5).for_each(|_| timing.update(false));
+        assert!(timing.jump_buffered());
+        timing.update(false);
+        assert!(!timing.jump_buffered());
+
+        timing.land();
+        (0..6).for_each(|_| timing.update(false));
+        assert!(timing.can_jump(6));
+        timing.update(false);
+        assert!(!timing.can_jump(6));
+        timing.update(true);
+        assert!(timing.can_jump(6));
+    }
+
+    #[test]
+    fn a_jump_pressed_just_before_landing_remembers_an_early_release() {
+        for (released, velocity) in [(false, -25), (true, -8)] {
+            let mut boy = running_boy(JumpParams::default());
+            let ground = boy.pos_y();
+            boy.jump();
+            while boy.velocity_y() < 0 || ground - boy.pos_y() > 60 {
+                boy.update();
+            }
+
+            boy.jump();
+            if released {
+                boy.release_jump();
+            }
+            assert_eq!(boy.state_name(), "Jumping");
+            for _ in 0..10 {
+                if boy.pos_y() == ground {
+                    break;
+                }
+                boy.update();
+            }
+
+            assert_eq!(boy.pos_y(), ground);
+            assert_eq!(boy.state_name(), "Jumping");
+            assert_eq!(boy.velocity_y(), velocity);
+        }
+    }
+
+    #[test]
+    fn rhb_falls_as_soon_as_he_walks_off_a_platform() {
+        let mut boy = running_boy(JumpParams::default());
+        let ground = boy.pos_y();
+        boy.land_on(400);
+        let mut heights = vec![boy.pos_y()];
+        for _ in 0..4 {
+            boy.update();
+            heights.push(boy.pos_y());
+        }
+
+        assert_eq!(boy.state_name(), "Airborne");
+        assert!(heights.windows(2).all(|pair| pair[0] < pair[1]));
+
+        for _ in 0..100 {
+            if boy.state_name() == "Running" {
+                break;
+            }
+            boy.update();
+        }
+        assert_eq!(boy.state_name(), "Running");
+        assert_eq!(boy.pos_y(), ground);
+    }
+
+    #[test]
+    fn rhb_can_still_jump_just_after_running_off_a_platform() {
+        let mut boy = running_boy(JumpParams::default());
+        boy.land_on(400);
+        (0..2).for_each(|_| boy.update());
+        assert_eq!(boy.state_name(), "Airborne");
+        boy.jump();
+        assert_eq!(boy.state_name(), "Jumping");
+        assert_eq!(boy.velocity_y(), -25);
+
+        let mut late = running_boy(JumpParams::default());
+        late.land_on(400);
+        (0..8).for_each(|_| late.update());
+        late.jump();
+        assert_eq!(late.state_name(), "Airborne");
+    }
+
+    #[test]
+    fn jump_timing_is_off_the_ground_until_something_lands_rhb() {
+        use red_hat_boy_states::JumpTiming;
+
+        let mut timing = JumpTiming::default();
+        assert!(timing.on_ground());
+
+        // 在平台上跑时每次 update 都会先离地, 再被平台的 land_on 放回去
+        timing.update(false);
+        assert!(!timing.on_ground());
+        timing.land();
+        assert!(timing.on_ground());
+    }
+
+    #[test]
+    fn game_over_ui_highlights_the_new_high_score() {
+        let mut high_scores = HighScoreTable::default();
+        high_scores.insert(HighScore { score: 90, seed: 1 });
+        let rank = high_scores.insert(HighScore { score: 40, seed: 2 });
+
+        let html = game_over_ui(40, &high_scores, rank);
+
+        assert!(html.contains("<li>90 (seed 1)</li><li class='new'>40 (seed 2)</li>"));
+        assert!(html.contains("id='new_game'"));
+    }
+
+    #[test]
+    fn settings_change_music_and_sfx_volume_separately() {
+        let storage: Rc<dyn Storage> = Rc::new(MemoryStorage::new());
+        let walk = headless_walk(storage.clone(), 7);
+        let mut volume = walk.audio.volume_settings();
+        volume.set_volume(Channel::Music, 0.5);
+        walk.set_volume_settings(volume);
+
+        let html = settings_ui(&walk.bindings, &walk.audio.volume_settings(), None);
+
+        assert!(html.contains("<td>Music</td><td><button id='volume_music_down'>-</button>50%"));
+        assert!(html.contains("id='volume_sfx_up'"));
+        assert_eq!(VolumeSettings::load(storage.as_ref()).unwrap().music, 0.5);
+        assert_eq!(
+            walk.boy
+                .state_machine
+                .context()
+                .audio
+                .volume_settings()
+                .music,
+            0.5
+        );
+    }
+
+    #[test]
+    fn music_keeps_the_same_song_playing_and_only_adjusts_it() {
+        assert_eq!(
+            MusicCue::between(None, (MusicTrack::Title, 1.0)),
+            MusicCue::Crossfade
+        );
+        assert_eq!(
+            MusicCue::between(Some((MusicTrack::Running, 1.0)), (MusicTrack::Running, 1.0)),
+            MusicCue::Keep
+        );
+        assert_eq!(
+            MusicCue::between(Some((MusicTrack::Title, 1.0)), (MusicTrack::Running, 1.0)),
+            MusicCue::Adjust {
+                volume: 1.0,
+                playback_rate: 1.0
+            }
+        );
+        assert_eq!(
+            MusicCue::between(
+                Some((MusicTrack::Running, 1.0)),
+                (MusicTrack::Running, PAUSED_MUSIC_VOLUME)
+            ),
+            MusicCue::Adjust {
+                volume: PAUSED_MUSIC_VOLUME,
+                playback_rate: 1.0
+            }
+        );
+        assert_eq!(
+            MusicCue::between(
+                Some((MusicTrack::Running, 1.0)),
+                (MusicTrack::GameOver, 1.0)
+            ),
+            MusicCue::Adjust {
+                volume: 0.5,
+                playback_rate: 0.8
+            }
+        );
+    }
+
+    #[test]
+    fn music_follows_the_state_machine() {
+        let mut game = WalkTheDog::with_walk(headless_walk(Rc::new(MemoryStorage::new()), 7), None);
+        let mut keystate = KeyState::new();
+        assert_eq!(machine(&game).music(), (MusicTrack::Title, 1.0));
+
+        keystate.tap("ArrowRight", 1);
+        game.update(&keystate).unwrap();
+        keystate.end_update();
+        assert_eq!(machine(&game).music(), (MusicTrack::Running, 1.0));
+
+        keystate.set_pressed("Escape");
+        game.update(&keystate).unwrap();
+        assert_eq!(
+            machine(&game).music(),
+            (MusicTrack::Running, PAUSED_MUSIC_VOLUME)
+        );
+    }
 }
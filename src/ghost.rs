@@ -0,0 +1,256 @@
+use std::cmp::Reverse;
+
+use anyhow::Result;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::storage::{self, Storage, Versioned};
+
+const GHOSTS_KEY: &str = "walk_the_dog.ghosts";
+// 版本 1 把动作伪装成按键存在 Replay 里, 读出来的时候直接丢掉
+const GHOSTS_VERSION: u32 = 2;
+pub const MAX_GHOST_RUNS: usize = 10;
+
+/// GhostActions 是一次 Walking update 里 RHB 收到的动作.
+/// 幽灵录像里记的是 RHB 实际执行的动作, 而不是玩家的按键,
+/// 这样暂停, 改键都不会让幽灵和障碍物错开
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GhostActions {
+    pub jump: bool,
+    pub release_jump: bool,
+    pub slide: bool,
+}
+
+const JUMP: u8 = 1;
+const RELEASE_JUMP: u8 = 1 << 1;
+const SLIDE: u8 = 1 << 2;
+
+impl From<GhostActions> for u8 {
+    fn from(actions: GhostActions) -> Self {
+        [
+            (actions.jump, JUMP),
+            (actions.release_jump, RELEASE_JUMP),
+            (actions.slide, SLIDE),
+        ]
+        .iter()
+        .filter(|(performed, _)| *performed)
+        .fold(0, |bits, (_, bit)| bits | bit)
+    }
+}
+
+impl From<u8> for GhostActions {
+    fn from(bits: u8) -> Self {
+        GhostActions {
+            jump: bits & JUMP != 0,
+            release_jump: bits & RELEASE_JUMP != 0,
+            slide: bits & SLIDE != 0,
+        }
+    }
+}
+
+/// GhostRecorder 记下每次 update 的 GhostActions
+#[derive(Debug, Default)]
+pub struct GhostRecorder {
+    actions: Vec<GhostActions>,
+}
+
+impl GhostRecorder {
+    pub fn record(&mut self, actions: GhostActions) {
+        self.actions.push(actions);
+    }
+
+    pub fn actions(&self) -> &[GhostActions] {
+        &self.actions
+    }
+}
+
+/// GhostInput 按顺序读出录下来的 GhostActions
+pub struct GhostInput {
+    actions: std::vec::IntoIter<GhostActions>,
+}
+
+impl GhostInput {
+    pub fn new(actions: Vec<GhostActions>) -> Self {
+        GhostInput {
+            actions: actions.into_iter(),
+        }
+    }
+
+    /// next_actions 在录像放完之后返回 None
+    pub fn next_actions(&mut self) -> Option<GhostActions> {
+        self.actions.next()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GhostRun {
+    pub seed: u64,
+    pub score: i32,
+    #[serde(default, with = "run_length")]
+    pub actions: Vec<GhostActions>,
+}
+
+// 大部分 update 里的动作和上一次一样, 存成 [动作, 连续次数] 可以让存档小很多
+mod run_length {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        actions: &[GhostActions],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let mut runs: Vec<(u8, u32)> = Vec::new();
+        for bits in actions.iter().map(|actions| u8::from(*actions)) {
+            match runs.last_mut() {
+                Some((last, count)) if *last == bits => *count += 1,
+                _ => runs.push((bits, 1)),
+            }
+        }
+        runs.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<GhostActions>, D::Error> {
+        let runs: Vec<(u8, u32)> = Vec::deserialize(deserializer)?;
+        Ok(runs
+            .into_iter()
+            .flat_map(|(bits, count)| {
+                std::iter::repeat(GhostActions::from(bits)).take(count as usize)
+            })
+            .collect())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedGhostRuns {
+    version: u32,
+    #[serde(default)]
+    runs: Vec<GhostRun>,
+}
+
+impl Versioned for SavedGhostRuns {
+    const VERSION: u32 = GHOSTS_VERSION;
+
+    fn version(&self) -> u32 {
+        self.version
+    }
+}
+
+/// GhostRuns 保存每个种子上分数最高的一局, 最多 MAX_GHOST_RUNS 个种子
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GhostRuns {
+    runs: Vec<GhostRun>,
+}
+
+impl GhostRuns {
+    pub fn load(storage: &dyn Storage) -> Result<Self> {
+        let saved: Option<SavedGhostRuns> = storage::load(storage, GHOSTS_KEY)?;
+        Ok(GhostRuns {
+            runs: saved
+                .filter(|saved| saved.version == GHOSTS_VERSION)
+                .map(|saved| saved.runs)
+                .unwrap_or_default(),
+        })
+    }
+
+    pub fn save(&self, storage: &dyn Storage) -> Result<()> {
+        storage::save(
+            storage,
+            GHOSTS_KEY,
+            &SavedGhostRuns {
+                version: GHOSTS_VERSION,
+                runs: self.runs.clone(),
+            },
+        )
+    }
+
+    pub fn best_for(&self, seed: u64) -> Option<&GhostRun> {
+        self.runs.iter().find(|run| run.seed == seed)
+    }
+
+    /// insert 只在超过这个种子原来的最好成绩时才替换, 返回这一局最后有没有留在表里.
+    /// 表满了的时候分数最低的一局会被挤掉, 可能就是刚放进来的这一局
+    pub fn insert(&mut self, run: GhostRun) -> bool {
+        let seed = run.seed;
+        if let Some(best) = self.runs.iter_mut().find(|best| best.seed == run.seed) {
+            if best.score >= run.score {
+                return false;
+            }
+            *best = run;
+        } else {
+            self.runs.push(run);
+        }
+        self.runs.sort_by_key(|run| Reverse(run.score));
+        self.runs.truncate(MAX_GHOST_RUNS);
+        self.best_for(seed).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ghost_input_plays_back_the_recorded_actions() {
+        let actions = [
+            GhostActions::default(),
+            GhostActions {
+                jump: true,
+                ..GhostActions::default()
+            },
+            GhostActions {
+                jump: true,
+                release_jump: true,
+                ..GhostActions::default()
+            },
+            GhostActions {
+                slide: true,
+                ..GhostActions::default()
+            },
+            GhostActions {
+                slide: true,
+                ..GhostActions::default()
+            },
+        ];
+        let mut recorder = GhostRecorder::default();
+        actions.iter().for_each(|action| recorder.record(*action));
+        let run = GhostRun {
+            seed: 3,
+            score: 1,
+            actions: recorder.actions().to_vec(),
+        };
+        let json = serde_json::to_string(&run).unwrap();
+        assert_eq!(
+            json,
+            r#"{"seed":3,"score":1,"actions":[[0,1],[1,1],[3,1],[4,2]]}"#
+        );
+
+        let run: GhostRun = serde_json::from_str(&json).unwrap();
+        let mut input = GhostInput::new(run.actions);
+        let played: Vec<GhostActions> = std::iter::from_fn(|| input.next_actions()).collect();
+
+        assert_eq!(played, actions);
+    }
+
+    #[test]
+    fn insert_keeps_only_the_best_run_per_seed() {
+        let run = |seed, score| GhostRun {
+            seed,
+            score,
+            actions: vec![],
+        };
+        let mut runs = GhostRuns::default();
+
+        assert!(runs.insert(run(1, 10)));
+        assert!(!runs.insert(run(1, 5)));
+        assert!(runs.insert(run(1, 20)));
+        (2..=MAX_GHOST_RUNS as u64).for_each(|seed| {
+            runs.insert(run(seed, 100));
+        });
+        assert!(!runs.insert(run(20, 1)));
+        assert!(runs.insert(run(MAX_GHOST_RUNS as u64 + 1, 100)));
+
+        assert_eq!(runs.best_for(1), None);
+        assert_eq!(runs.best_for(20), None);
+        assert_eq!(runs.best_for(2).unwrap().score, 100);
+    }
+}
@@ -0,0 +1,163 @@
+use std::cmp::Reverse;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::storage::{self, Storage, Versioned};
+
+const HIGH_SCORES_KEY: &str = "walk_the_dog.high_scores";
+// 存档格式的版本号, 新增字段时都要带上 #[serde(default)], 这样旧存档仍然可以读出来
+const HIGH_SCORES_VERSION: u32 = 1;
+pub const MAX_HIGH_SCORES: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HighScore {
+    pub score: i32,
+    #[serde(default)]
+    pub seed: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedHighScores {
+    version: u32,
+    #[serde(default)]
+    entries: Vec<HighScore>,
+}
+
+impl Versioned for SavedHighScores {
+    const VERSION: u32 = HIGH_SCORES_VERSION;
+
+    fn version(&self) -> u32 {
+        self.version
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HighScoreTable {
+    entries: Vec<HighScore>,
+}
+
+impl HighScoreTable {
+    pub fn load(storage: &dyn Storage) -> Result<Self> {
+        let saved: Option<SavedHighScores> = storage::load(storage, HIGH_SCORES_KEY)?;
+        let mut table = HighScoreTable {
+            entries: saved.map(|saved| saved.entries).unwrap_or_default(),
+        };
+        table.sort();
+        Ok(table)
+    }
+
+    pub fn save(&self, storage: &dyn Storage) -> Result<()> {
+        storage::save(
+            storage,
+            HIGH_SCORES_KEY,
+            &SavedHighScores {
+                version: HIGH_SCORES_VERSION,
+                entries: self.entries.clone(),
+            },
+        )
+    }
+
+    /// insert 把新的成绩放进表里, 如果进入了前 MAX_HIGH_SCORES 名就返回它的名次(从 0 开始).
+    /// 一分都没有的成绩不算
+    pub fn insert(&mut self, high_score: HighScore) -> Option<usize> {
+        if high_score.score <= 0 {
+            return None;
+        }
+        let rank = self
+            .entries
+            .iter()
+            .position(|entry| entry.score < high_score.score)
+            .unwrap_or(self.entries.len());
+        if rank >= MAX_HIGH_SCORES {
+            return None;
+        }
+        self.entries.insert(rank, high_score);
+        self.entries.truncate(MAX_HIGH_SCORES);
+        Some(rank)
+    }
+
+    pub fn entries(&self) -> &[HighScore] {
+        &self.entries
+    }
+
+    pub fn best(&self) -> Option<&HighScore> {
+        self.entries.first()
+    }
+
+    fn sort(&mut self) {
+        self.entries.sort_by_key(|entry| Reverse(entry.score));
+        self.entries.truncate(MAX_HIGH_SCORES);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn insert_keeps_the_table_sorted_and_bounded() {
+        let mut table = HighScoreTable::default();
+        assert_eq!(table.insert(HighScore { score: 0, seed: 0 }), None);
+        for score in 1..=MAX_HIGH_SCORES as i32 {
+            table.insert(HighScore { score, seed: 0 });
+        }
+
+        assert_eq!(table.insert(HighScore { score: 1, seed: 0 }), None);
+        assert_eq!(table.insert(HighScore { score: 6, seed: 7 }), Some(5));
+        assert_eq!(table.entries().len(), MAX_HIGH_SCORES);
+        assert_eq!(table.best().unwrap().score, 10);
+        assert_eq!(table.entries().last().unwrap().score, 2);
+    }
+
+    #[test]
+    fn table_round_trips_through_storage() {
+        let storage = MemoryStorage::new();
+        let mut table = HighScoreTable::default();
+        table.insert(HighScore { score: 42, seed: 3 });
+        table.save(&storage).unwrap();
+
+        assert_eq!(HighScoreTable::load(&storage).unwrap(), table);
+    }
+
+    #[test]
+    fn load_fills_in_missing_fields() {
+        let storage = MemoryStorage::new();
+        storage
+            .set(
+                HIGH_SCORES_KEY,
+                r#"{"version":1,"entries":[{"score":3},{"score":8,"seed":1}]}"#,
+            )
+            .unwrap();
+
+        let table = HighScoreTable::load(&storage).unwrap();
+
+        assert_eq!(
+            table.entries(),
+            &[
+                HighScore { score: 8, seed: 1 },
+                HighScore { score: 3, seed: 0 }
+            ]
+        );
+    }
+
+    #[test]
+    fn saves_from_a_newer_version_are_neither_loaded_nor_overwritten() {
+        let storage = MemoryStorage::new();
+        let newer = r#"{"version":2,"entries":[{"score":8,"seed":1,"name":"RHB"}]}"#;
+        storage.set(HIGH_SCORES_KEY, newer).unwrap();
+
+        assert!(HighScoreTable::load(&storage).is_err());
+        let mut table = HighScoreTable::default();
+        table.insert(HighScore { score: 3, seed: 0 });
+        assert!(table.save(&storage).is_err());
+        assert_eq!(
+            storage.get(HIGH_SCORES_KEY).unwrap().as_deref(),
+            Some(newer)
+        );
+
+        storage.set(HIGH_SCORES_KEY, "not json").unwrap();
+        assert!(table.save(&storage).is_err());
+    }
+}
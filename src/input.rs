@@ -0,0 +1,345 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    engine::{
+        KeyState, DEBUG_OVERLAY_KEY, DOWNLOAD_REPLAY_KEY, GAMEPAD_A, GAMEPAD_B, GAMEPAD_DOWN,
+        GAMEPAD_RIGHT, GAMEPAD_START, TOUCH_SWIPE_DOWN, TOUCH_TAP, UPLOAD_REPLAY_KEY,
+    },
+    storage::{self, Storage, Versioned},
+};
+
+const BINDINGS_KEY: &str = "walk_the_dog.bindings";
+const BINDINGS_VERSION: u32 = 1;
+
+// 屏幕上的按钮对应的虚拟按键, 按钮的位置见 game.rs 里的 touch_layout
+pub const TOUCH_JUMP_BUTTON: &str = "Touch:Jump";
+pub const TOUCH_SLIDE_BUTTON: &str = "Touch:Slide";
+
+// 这些按键有固定的用途, 不能绑定到 Action 上
+pub const SETTINGS_KEY: &str = "KeyC";
+pub const BOUNDING_BOX_KEY: &str = "F2";
+pub const GHOST_KEY: &str = "F4";
+pub const MUTE_KEY: &str = "KeyM";
+pub const VOLUME_DOWN_KEY: &str = "Minus";
+pub const VOLUME_UP_KEY: &str = "Equal";
+const RESERVED_KEYS: [&str; 9] = [
+    SETTINGS_KEY,
+    BOUNDING_BOX_KEY,
+    GHOST_KEY,
+    MUTE_KEY,
+    VOLUME_DOWN_KEY,
+    VOLUME_UP_KEY,
+    DEBUG_OVERLAY_KEY,
+    DOWNLOAD_REPLAY_KEY,
+    UPLOAD_REPLAY_KEY,
+];
+
+/// Action 是游戏里的操作, 具体由哪些按键触发由 InputBindings 决定
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Action {
+    Jump,
+    Slide,
+    Start,
+    Pause,
+}
+
+impl Action {
+    pub const ALL: [Action; 4] = [Action::Jump, Action::Slide, Action::Start, Action::Pause];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Action::Jump => "Jump",
+            Action::Slide => "Slide",
+            Action::Start => "Start",
+            Action::Pause => "Pause",
+        }
+    }
+
+    fn default_keys(&self) -> Vec<String> {
+        let keys: &[&str] = match self {
+            Action::Jump => &["Space"],
+            Action::Slide => &["ArrowDown"],
+            Action::Start => &["ArrowRight"],
+            Action::Pause => &["Escape", "KeyP"],
+        };
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    /// virtual_keys 是触摸和手柄等非键盘输入对应的虚拟按键, 它们不能在设置里修改
+    fn virtual_keys(&self) -> &'static [&'static str] {
+        match self {
+            Action::Jump => &[TOUCH_TAP, TOUCH_JUMP_BUTTON, GAMEPAD_A],
+            Action::Slide => &[
+                TOUCH_SWIPE_DOWN,
+                TOUCH_SLIDE_BUTTON,
+                GAMEPAD_B,
+                GAMEPAD_DOWN,
+            ],
+            Action::Start => &[TOUCH_TAP, TOUCH_JUMP_BUTTON, GAMEPAD_A, GAMEPAD_RIGHT],
+            Action::Pause => &[GAMEPAD_START],
+        }
+    }
+}
+
+pub fn is_reserved(code: &str) -> bool {
+    RESERVED_KEYS.contains(&code)
+}
+
+/// is_virtual 判断 code 是不是由其他输入设备转换来的虚拟按键, 比如 `Touch:Tap`
+pub fn is_virtual(code: &str) -> bool {
+    code.contains(':')
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedBindings {
+    version: u32,
+    #[serde(default)]
+    bindings: HashMap<Action, Vec<String>>,
+}
+
+impl Versioned for SavedBindings {
+    const VERSION: u32 = BINDINGS_VERSION;
+
+    fn version(&self) -> u32 {
+        self.version
+    }
+}
+
+/// InputBindings 把 Action 映射到 KeyboardEvent.code, 建立在 KeyState 之上
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct InputBindings {
+    bindings: HashMap<Action, Vec<String>>,
+}
+
+impl Default for InputBindings {
+    fn default() -> Self {
+        InputBindings {
+            bindings: Action::ALL
+                .iter()
+                .map(|action| (*action, action.default_keys()))
+                .collect(),
+        }
+    }
+}
+
+impl InputBindings {
+    /// load 读取保存的按键设置, 和 bind 一样保留的按键不会被绑定, 同一个按键只留给
+    /// Action::ALL 里第一个用到它的 Action. 存档里没有可用按键的 Action 使用没被占用的
+    /// 默认按键, 默认按键也都被占用时整个存档都不用
+    pub fn load(storage: &dyn Storage) -> Result<Self> {
+        let saved = match storage::load::<SavedBindings>(storage, BINDINGS_KEY)? {
+            Some(saved) => saved.bindings,
+            None => return Ok(InputBindings::default()),
+        };
+        let mut claimed: Vec<String> = Vec::new();
+        let mut claim = |keys: &[String]| -> Vec<String> {
+            let mut kept = Vec::new();
+            for key in keys {
+                if !is_reserved(key) && !claimed.contains(key) {
+                    claimed.push(key.clone());
+                    kept.push(key.clone());
+                }
+            }
+            kept
+        };
+
+        let mut bindings = HashMap::new();
+        for action in Action::ALL {
+            let keys = claim(saved.get(&action).map(Vec::as_slice).unwrap_or_default());
+            if !keys.is_empty() {
+                bindings.insert(action, keys);
+            }
+        }
+        for action in Action::ALL {
+            if bindings.contains_key(&action) {
+                continue;
+            }
+            let keys = claim(&action.default_keys());
+            if keys.is_empty() {
+                return Ok(InputBindings::default());
+            }
+            bindings.insert(action, keys);
+        }
+        Ok(InputBindings { bindings })
+    }
+
+    pub fn save(&self, storage: &dyn Storage) -> Result<()> {
+        storage::save(
+            storage,
+            BINDINGS_KEY,
+            &SavedBindings {
+                version: BINDINGS_VERSION,
+                bindings: self.bindings.clone(),
+            },
+        )
+    }
+
+    pub fn keys(&self, action: Action) -> &[String] {
+        self.bindings
+            .get(&action)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    fn codes(&self, action: Action) -> impl Iterator<Item = &str> {
+        self.keys(action)
+            .iter()
+            .map(String::as_str)
+            .chain(action.virtual_keys().iter().copied())
+    }
+
+    pub fn is_active(&self, action: Action, keystate: &KeyState) -> bool {
+        self.codes(action).any(|key| keystate.is_pressed(key))
+    }
+
+    pub fn just_pressed(&self, action: Action, keystate: &KeyState) -> bool {
+        self.codes(action).any(|key| keystate.just_pressed(key))
+    }
+
+    pub fn just_released(&self, action: Action, keystate: &KeyState) -> bool {
+        self.codes(action).any(|key| keystate.just_released(key))
+    }
+
+    /// bind 把 action 绑定到 code 上, 同一个按键不会同时触发两个 Action.
+    /// 保留的按键, 以及其他 Action 唯一的按键都不能绑定
+    pub fn bind(&mut self, action: Action, code: &str) -> Result<()> {
+        if is_reserved(code) {
+            return Err(anyhow!("{} is reserved", key_label(code)));
+        }
+        if let Some(owner) = Action::ALL
+            .iter()
+            .filter(|owner| **owner != action)
+            .find(|owner| matches!(self.keys(**owner), [key] if key == code))
+        {
+            return Err(anyhow!(
+                "{} is the only key for {}",
+                key_label(code),
+                owner.label()
+            ));
+        }
+        self.bindings
+            .values_mut()
+            .for_each(|keys| keys.retain(|key| key != code));
+        self.bindings.insert(action, vec![code.to_string()]);
+        Ok(())
+    }
+
+    /// label 返回给玩家看的按键名称, 比如 `KeyA` 显示为 `A`
+    pub fn label(&self, action: Action) -> String {
+        let keys: Vec<&str> = self.keys(action).iter().map(|key| key_label(key)).collect();
+        if keys.is_empty() {
+            "-".to_string()
+        } else {
+            keys.join(" / ")
+        }
+    }
+}
+
+pub fn key_label(code: &str) -> &str {
+    ["Key", "Digit", "Arrow"]
+        .iter()
+        .find_map(|prefix| code.strip_prefix(prefix))
+        .filter(|label| !label.is_empty())
+        .unwrap_or(code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn bind_moves_a_key_away_from_its_previous_action() {
+        let mut bindings = InputBindings::default();
+        let mut keystate = KeyState::new();
+        keystate.set_pressed("KeyW");
+
+        bindings.bind(Action::Jump, "KeyW").unwrap();
+        bindings.bind(Action::Slide, "Space").unwrap();
+
+        assert!(bindings.is_active(Action::Jump, &keystate));
+        assert!(bindings.keys(Action::Jump).iter().all(|key| key != "Space"));
+        assert_eq!(bindings.label(Action::Slide), "Space");
+        assert_eq!(bindings.label(Action::Pause), "Escape / P");
+    }
+
+    #[test]
+    fn bind_rejects_reserved_keys_and_keeps_every_action_bound() {
+        let mut bindings = InputBindings::default();
+
+        assert!(bindings.bind(Action::Jump, MUTE_KEY).is_err());
+        assert!(bindings.bind(Action::Jump, "ArrowRight").is_err());
+        assert!(bindings.bind(Action::Start, "ArrowRight").is_ok());
+        assert_eq!(bindings, InputBindings::default());
+        assert!(bindings.bind(Action::Jump, "KeyP").is_ok());
+        assert_eq!(bindings.label(Action::Pause), "Escape");
+
+        let storage = MemoryStorage::new();
+        storage
+            .set(
+                BINDINGS_KEY,
+                r#"{"version":1,"bindings":{"Jump":["KeyM"],"Slide":["KeyS","F8"]}}"#,
+            )
+            .unwrap();
+        let loaded = InputBindings::load(&storage).unwrap();
+        assert_eq!(loaded.keys(Action::Jump), ["Space"]);
+        assert_eq!(loaded.keys(Action::Slide), ["KeyS"]);
+
+        // 存档里重复的按键只留给第一个 Action, 否则一个按键会同时触发两个 Action
+        storage
+            .set(
+                BINDINGS_KEY,
+                r#"{"version":1,"bindings":{"Jump":["KeyS"],"Slide":["KeyS","KeyX"],"Start":["KeyS"]}}"#,
+            )
+            .unwrap();
+        let loaded = InputBindings::load(&storage).unwrap();
+        assert_eq!(loaded.keys(Action::Jump), ["KeyS"]);
+        assert_eq!(loaded.keys(Action::Slide), ["KeyX"]);
+        assert_eq!(loaded.keys(Action::Start), ["ArrowRight"]);
+
+        // 默认按键被别的 Action 占用之后 Slide 没有按键可用, 整个存档都不用
+        storage
+            .set(
+                BINDINGS_KEY,
+                r#"{"version":1,"bindings":{"Jump":["ArrowDown"]}}"#,
+            )
+            .unwrap();
+        assert_eq!(
+            InputBindings::load(&storage).unwrap(),
+            InputBindings::default()
+        );
+    }
+
+    #[test]
+    fn touch_input_triggers_actions_without_being_remappable() {
+        let mut bindings = InputBindings::default();
+        bindings.bind(Action::Jump, "KeyW").unwrap();
+        let mut keystate = KeyState::new();
+        keystate.tap(TOUCH_TAP, 1);
+
+        assert!(bindings.just_pressed(Action::Jump, &keystate));
+        assert!(bindings.is_active(Action::Start, &keystate));
+        assert!(!bindings.is_active(Action::Slide, &keystate));
+        assert_eq!(bindings.label(Action::Jump), "W");
+    }
+
+    #[test]
+    fn load_falls_back_to_defaults_for_missing_actions() {
+        let storage = MemoryStorage::new();
+        storage
+            .set(
+                BINDINGS_KEY,
+                r#"{"version":1,"bindings":{"Jump":["KeyZ"]}}"#,
+            )
+            .unwrap();
+
+        let bindings = InputBindings::load(&storage).unwrap();
+
+        assert_eq!(bindings.keys(Action::Jump), ["KeyZ".to_string()]);
+        assert_eq!(bindings.keys(Action::Slide), ["ArrowDown".to_string()]);
+    }
+}
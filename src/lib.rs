@@ -1,9 +1,15 @@
 #[macro_use]
 pub mod browser;
+pub mod assets;
 pub mod engine;
 pub mod game;
+pub mod ghost;
+pub mod high_score;
+pub mod input;
+pub mod replay;
 pub mod segment;
 pub mod sound;
+pub mod storage;
 
 use anyhow::Result;
 use engine::GameLoop;
@@ -26,9 +32,9 @@ pub fn main_js() -> Result<(), JsValue> {
 
     browser::spawn_local(async move {
         let game = game::WalkTheDog::new();
-        GameLoop::start(game)
-            .await
-            .expect("Could not start game loop");
+        if let Err(err) = GameLoop::start(game).await {
+            error!("Could not start game loop {:#?}", err);
+        }
     });
     Ok(())
 }
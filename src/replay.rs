@@ -0,0 +1,196 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::engine::{KeyEvent, KeyState};
+
+const REPLAY_VERSION: u32 = 1;
+
+/// Replay 是一局游戏的录像: 随机种子加上每次 update 之前的按键变化.
+/// 用同样的种子和设置重新开始, 再按顺序喂回这些按键, 就能得到完全相同的结果
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Replay {
+    pub version: u32,
+    pub seed: u64,
+    /// 游戏在这一局开始时的设置, 比如按键绑定, 回放时由游戏自己解释
+    #[serde(default)]
+    pub context: serde_json::Value,
+    /// 录像一共覆盖了多少次 update
+    pub updates: u32,
+    #[serde(default)]
+    pub events: Vec<ReplayEvent>,
+}
+
+/// ReplayEvent 用差值记录时间, 字段名也尽量短, 这样录像文件比较小
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayEvent {
+    /// 距离上一个事件隔了多少次 update
+    #[serde(rename = "d")]
+    pub delta: u32,
+    #[serde(rename = "k")]
+    pub code: String,
+    #[serde(rename = "p")]
+    pub pressed: bool,
+}
+
+impl Replay {
+    pub fn from_json(json: &str) -> Result<Self> {
+        let replay: Replay =
+            serde_json::from_str(json).map_err(|err| anyhow!("Could not parse replay: {}", err))?;
+        if replay.version > REPLAY_VERSION {
+            return Err(anyhow!("Unsupported replay version {}", replay.version));
+        }
+        Ok(replay)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        serde_json::to_string(self).map_err(|err| anyhow!("Could not serialize replay: {}", err))
+    }
+}
+
+pub struct ReplayRecorder {
+    seed: u64,
+    context: serde_json::Value,
+    updates: u32,
+    last_event_update: u32,
+    events: Vec<ReplayEvent>,
+}
+
+impl ReplayRecorder {
+    pub fn new(seed: u64, context: serde_json::Value) -> Self {
+        ReplayRecorder {
+            seed,
+            context,
+            updates: 0,
+            last_event_update: 0,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn updates(&self) -> u32 {
+        self.updates
+    }
+
+    /// record 在每次 update 之前调用, 记下从上一次 update 到现在的按键变化
+    pub fn record(&mut self, keystate: &KeyState) {
+        keystate.events().iter().for_each(|event| {
+            self.events.push(ReplayEvent {
+                delta: self.updates - self.last_event_update,
+                code: event.code.clone(),
+                pressed: event.pressed,
+            });
+            self.last_event_update = self.updates;
+        });
+        self.updates += 1;
+    }
+
+    pub fn to_replay(&self) -> Replay {
+        Replay {
+            version: REPLAY_VERSION,
+            seed: self.seed,
+            context: self.context.clone(),
+            updates: self.updates,
+            events: self.events.clone(),
+        }
+    }
+}
+
+/// ReplayPlayer 按录像重建每次 update 看到的 KeyState
+pub struct ReplayPlayer {
+    replay: Replay,
+    keystate: KeyState,
+    update: u32,
+    next_event: usize,
+    next_event_update: u32,
+}
+
+impl ReplayPlayer {
+    pub fn new(replay: Replay) -> Self {
+        let next_event_update = replay.events.first().map_or(0, |event| event.delta);
+        ReplayPlayer {
+            replay,
+            keystate: KeyState::new(),
+            update: 0,
+            next_event: 0,
+            next_event_update,
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.replay.seed
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.update >= self.replay.updates
+    }
+
+    /// next_keystate 返回下一次 update 要用的 KeyState, 录像放完之后返回 None.
+    /// 调用方在 update 之后需要对它调用 end_update
+    pub fn next_keystate(&mut self) -> Option<&mut KeyState> {
+        if self.is_finished() {
+            return None;
+        }
+        while let Some(event) = self.replay.events.get(self.next_event) {
+            if self.next_event_update != self.update {
+                break;
+            }
+            self.keystate.apply(&KeyEvent {
+                code: event.code.clone(),
+                pressed: event.pressed,
+            });
+            self.next_event += 1;
+            self.next_event_update += self
+                .replay
+                .events
+                .get(self.next_event)
+                .map_or(0, |event| event.delta);
+        }
+        self.update += 1;
+        Some(&mut self.keystate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn player_rebuilds_the_recorded_key_edges() {
+        let mut live = KeyState::new();
+        let mut recorder = ReplayRecorder::new(42, serde_json::json!({ "Jump": ["Space"] }));
+        let mut recorded = Vec::new();
+        for update in 0..6 {
+            match update {
+                1 => live.set_pressed("Space"),
+                // 同一次 update 之前按下又松开
+                3 => {
+                    live.set_released("Space");
+                    live.set_pressed("ArrowDown");
+                    live.set_released("ArrowDown");
+                }
+                _ => {}
+            }
+            recorder.record(&live);
+            recorded.push((live.just_pressed("Space"), live.just_pressed("ArrowDown")));
+            live.end_update();
+        }
+
+        let replay = Replay::from_json(&recorder.to_replay().to_json().unwrap()).unwrap();
+        let mut player = ReplayPlayer::new(replay);
+        let mut replayed = Vec::new();
+        while let Some(keystate) = player.next_keystate() {
+            replayed.push((
+                keystate.just_pressed("Space"),
+                keystate.just_pressed("ArrowDown"),
+            ));
+            keystate.end_update();
+        }
+
+        assert_eq!(player.seed(), 42);
+        assert!(player.is_finished());
+        assert_eq!(replayed, recorded);
+    }
+}
@@ -1,90 +1,267 @@
-use std::rc::Rc;
+use std::{collections::HashMap, rc::Rc};
 
-use web_sys::HtmlImageElement;
+use anyhow::Result;
+use serde::Deserialize;
 
 use crate::{
-    engine::{Image, Point, Rect, SpriteSheet},
+    engine::{EngineError, Image, ImageHandle, Point, Rect, SpriteSheet},
     game::{Barrier, Obstacle, Platform},
 };
 
-const LOW_PLATFORM: i16 = 420;
-const HIGH_PLATFORM: i16 = 375;
-const FIRST_PLATFORM: i16 = 370;
-const STONE_ON_GROUND: i16 = 546;
-
-const FLOATING_PLATFORM_SPRITES: [&str; 3] = ["13.png", "14.png", "15.png"];
-
-const PLATFORM_WIDTH: i16 = 384;
-const PLATFORM_HEIGHT: i16 = 93;
-const PLATFORM_EDGE_WIDTH: i16 = 60;
-const PLATFORM_EDGE_HEIGHT: i16 = 54;
-
-const FLOATING_PLATFORM_BOUNDING_BOXES: [Rect; 3] = [
-    Rect::new_from_x_y(0, 0, PLATFORM_EDGE_WIDTH, PLATFORM_EDGE_HEIGHT),
-    Rect::new_from_x_y(
-        60,
-        0,
-        PLATFORM_WIDTH - (PLATFORM_EDGE_WIDTH * 2),
-        PLATFORM_HEIGHT,
-    ),
-    Rect::new_from_x_y(384 - 60, 0, PLATFORM_EDGE_WIDTH, PLATFORM_EDGE_HEIGHT),
-];
-
-pub fn stone_and_platform(
-    stone: HtmlImageElement,
-    sprite_sheet: Rc<SpriteSheet>,
+/// SegmentSheet 对应 segments.json, 描述了所有可以随机生成的障碍物片段
+#[derive(Debug, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SegmentSheet {
+    pub starting_segment: String,
+    pub segments: Vec<SegmentDefinition>,
+}
+
+impl SegmentSheet {
+    pub fn starting_segment(&self) -> Option<&SegmentDefinition> {
+        self.segments
+            .iter()
+            .find(|segment| segment.name == self.starting_segment)
+    }
+
+    /// barrier_images 返回所有片段里用到的障碍物图片, 在启动时需要提前加载
+    pub fn barrier_images(&self) -> Vec<&str> {
+        let mut images: Vec<&str> = self
+            .segments
+            .iter()
+            .flat_map(|segment| segment.obstacles.iter())
+            .filter_map(|obstacle| match obstacle {
+                ObstacleDefinition::Barrier { image, .. } => Some(image.as_str()),
+                ObstacleDefinition::Platform { .. } => None,
+            })
+            .collect();
+        images.sort_unstable();
+        images.dedup();
+        images
+    }
+
+    /// validate 检查每个片段都能完整地生成出来. 生成出来的片段如果是空的,
+    /// 下一个片段就会从头开始排, 叠在玩家身上
+    pub fn validate(&self, assets: &SegmentAssets) -> Result<()> {
+        if self.starting_segment().is_none() {
+            return Err(invalid(format!(
+                "starting segment {} not found",
+                self.starting_segment
+            )));
+        }
+        self.segments
+            .iter()
+            .try_for_each(|segment| segment.validate(assets))
+    }
+}
+
+fn invalid(reason: String) -> anyhow::Error {
+    EngineError::Asset(format!("Invalid segments.json: {}", reason)).into()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SegmentDefinition {
+    pub name: String,
+    pub obstacles: Vec<ObstacleDefinition>,
+}
+
+impl SegmentDefinition {
+    fn validate(&self, assets: &SegmentAssets) -> Result<()> {
+        if self.obstacles.is_empty() {
+            return Err(invalid(format!("segment {} has no obstacles", self.name)));
+        }
+        self.obstacles
+            .iter()
+            .try_for_each(|obstacle| match obstacle {
+                ObstacleDefinition::Barrier { image, .. } if !assets.images.contains_key(image) => {
+                    Err(invalid(format!(
+                        "image {} in segment {} is not loaded",
+                        image, self.name
+                    )))
+                }
+                ObstacleDefinition::Platform { bounding_boxes, .. }
+                    if bounding_boxes.is_empty() =>
+                {
+                    Err(invalid(format!(
+                        "a platform in segment {} has no bounding boxes",
+                        self.name
+                    )))
+                }
+                ObstacleDefinition::Platform { sprites, .. } => match sprites
+                    .iter()
+                    .find(|sprite| assets.sprite_sheet.cell(sprite).is_none())
+                {
+                    Some(sprite) => Err(invalid(format!(
+                        "sprite {} in segment {} is not in the sprite sheet",
+                        sprite, self.name
+                    ))),
+                    None => Ok(()),
+                },
+                ObstacleDefinition::Barrier { .. } => Ok(()),
+            })
+    }
+}
+
+/// offset 是障碍物相对于片段起点的位置, bounding_boxes 是相对于平台左上角的碰撞盒
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum ObstacleDefinition {
+    Barrier {
+        image: String,
+        offset: Point,
+    },
+    #[serde(rename_all = "camelCase")]
+    Platform {
+        offset: Point,
+        sprites: Vec<String>,
+        bounding_boxes: Vec<BoundingBox>,
+    },
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct BoundingBox {
+    pub x: i16,
+    pub y: i16,
+    pub width: i16,
+    pub height: i16,
+}
+
+impl From<&BoundingBox> for Rect {
+    fn from(bounding_box: &BoundingBox) -> Self {
+        Rect::new_from_x_y(
+            bounding_box.x,
+            bounding_box.y,
+            bounding_box.width,
+            bounding_box.height,
+        )
+    }
+}
+
+/// SegmentAssets 持有生成障碍物时需要的图片资源
+pub struct SegmentAssets {
+    pub sprite_sheet: Rc<SpriteSheet>,
+    pub images: HashMap<String, ImageHandle>,
+}
+
+pub fn create_segment(
+    definition: &SegmentDefinition,
+    assets: &SegmentAssets,
     offset_x: i16,
 ) -> Vec<Box<dyn Obstacle>> {
-    const INITIAL_STONE_OFFSET: i16 = 150;
-    vec![
-        Box::new(Barrier::new(Image::new(
-            stone,
-            Point {
-                x: offset_x + INITIAL_STONE_OFFSET,
-                y: STONE_ON_GROUND,
-            },
-        ))),
-        Box::new(create_floating_platform(
-            sprite_sheet,
-            Point {
-                x: offset_x + FIRST_PLATFORM,
-                y: LOW_PLATFORM,
-            },
-        )),
-    ]
+    definition
+        .obstacles
+        .iter()
+        .filter_map(|obstacle| create_obstacle(obstacle, assets, offset_x))
+        .collect()
 }
 
-pub fn platform_and_stone(
-    stone: HtmlImageElement,
-    sprite_sheet: Rc<SpriteSheet>,
+fn create_obstacle(
+    definition: &ObstacleDefinition,
+    assets: &SegmentAssets,
     offset_x: i16,
-) -> Vec<Box<dyn Obstacle>> {
-    const INITIAL_STONE_OFFSET: i16 = 400;
-    const INITIAL_PLATFORM_OFFSET: i16 = 200;
-
-    vec![
-        Box::new(Barrier::new(Image::new(
-            stone,
-            Point {
-                x: offset_x + INITIAL_STONE_OFFSET,
-                y: STONE_ON_GROUND,
-            },
-        ))),
-        Box::new(create_floating_platform(
-            sprite_sheet,
-            Point {
-                x: offset_x + INITIAL_PLATFORM_OFFSET,
-                y: HIGH_PLATFORM,
-            },
-        )),
-    ]
+) -> Option<Box<dyn Obstacle>> {
+    match definition {
+        ObstacleDefinition::Barrier { image, offset } => {
+            let Some(element) = assets.images.get(image) else {
+                error!("Barrier image {} was not loaded", image);
+                return None;
+            };
+            Some(Box::new(Barrier::new(Image::new(
+                element.clone(),
+                Point {
+                    x: offset_x + offset.x,
+                    y: offset.y,
+                },
+            ))))
+        }
+        ObstacleDefinition::Platform {
+            offset,
+            sprites,
+            bounding_boxes,
+        } => {
+            let sprite_names: Vec<&str> = sprites.iter().map(String::as_str).collect();
+            let bounding_boxes: Vec<Rect> = bounding_boxes.iter().map(Rect::from).collect();
+            Some(Box::new(Platform::new(
+                assets.sprite_sheet.clone(),
+                Point {
+                    x: offset_x + offset.x,
+                    y: offset.y,
+                },
+                &sprite_names,
+                &bounding_boxes,
+            )))
+        }
+    }
 }
 
-fn create_floating_platform(sprite_sheet: Rc<SpriteSheet>, position: Point) -> Platform {
-    Platform::new(
-        sprite_sheet,
-        position,
-        &FLOATING_PLATFORM_SPRITES,
-        &FLOATING_PLATFORM_BOUNDING_BOXES,
-    )
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::Sheet;
+
+    fn bundled_assets() -> SegmentAssets {
+        let tiles: Sheet = serde_json::from_str(include_str!("../static/tiles.json")).unwrap();
+        SegmentAssets {
+            sprite_sheet: Rc::new(SpriteSheet::new(
+                tiles,
+                ImageHandle::headless("tiles.png", 650, 485),
+            )),
+            images: HashMap::from([(
+                "Stone.png".to_string(),
+                ImageHandle::headless("Stone.png", 90, 54),
+            )]),
+        }
+    }
+
+    #[test]
+    fn validate_rejects_segments_that_would_be_empty() {
+        let mut sheet: SegmentSheet =
+            serde_json::from_str(include_str!("../static/segments.json")).unwrap();
+        let mut assets = bundled_assets();
+        assert!(sheet.validate(&assets).is_ok());
+
+        assets.images.clear();
+        assert_eq!(
+            sheet
+                .validate(&assets)
+                .unwrap_err()
+                .downcast::<EngineError>()
+                .unwrap(),
+            EngineError::Asset(
+                "Invalid segments.json: image Stone.png in segment stone_and_platform is not loaded"
+                    .into()
+            )
+        );
+
+        sheet.segments.push(SegmentDefinition {
+            name: "empty".into(),
+            obstacles: vec![],
+        });
+        assert!(sheet.validate(&bundled_assets()).is_err());
+    }
+
+    #[test]
+    fn bundled_segments_json_is_valid() {
+        let sheet: SegmentSheet =
+            serde_json::from_str(include_str!("../static/segments.json")).unwrap();
+
+        let starting = sheet.starting_segment().unwrap();
+        assert_eq!(starting.name, "stone_and_platform");
+        assert_eq!(sheet.barrier_images(), vec!["Stone.png"]);
+
+        match &starting.obstacles[1] {
+            ObstacleDefinition::Platform {
+                offset,
+                sprites,
+                bounding_boxes,
+            } => {
+                assert_eq!(*offset, Point { x: 370, y: 420 });
+                assert_eq!(sprites.len(), 3);
+                assert_eq!(
+                    Rect::from(&bounding_boxes[1]),
+                    Rect::new_from_x_y(60, 0, 264, 93)
+                );
+            }
+            ObstacleDefinition::Barrier { .. } => panic!("Expected a platform"),
+        }
+    }
 }
@@ -1,8 +1,19 @@
+use std::{cell::Cell, rc::Rc};
+
 use anyhow::{anyhow, Result};
 use js_sys::ArrayBuffer;
+use serde::{Deserialize, Serialize};
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::JsFuture;
-use web_sys::{AudioBuffer, AudioBufferSourceNode, AudioContext, AudioDestinationNode, AudioNode};
+use web_sys::{
+    AudioBuffer, AudioBufferSourceNode, AudioContext, AudioContextState, AudioNode, GainNode,
+    OscillatorType,
+};
+
+use crate::storage::{self, Storage, Versioned};
+
+const VOLUME_KEY: &str = "walk_the_dog.volume";
+const VOLUME_VERSION: u32 = 1;
 
 pub enum LOOPPING {
     NO,
@@ -13,19 +24,70 @@ pub fn create_audio_context() -> Result<AudioContext> {
     AudioContext::new().map_err(|err| anyhow!("Could not create audio context: {:#?}", err))
 }
 
+/// is_suspended 为 true 说明浏览器还没有允许播放声音, 需要等到用户的第一次操作
+pub fn is_suspended(ctx: &AudioContext) -> bool {
+    ctx.state() == AudioContextState::Suspended
+}
+
+/// resume 要在用户手势的事件处理函数里调用才会生效
+pub fn resume(ctx: &AudioContext) -> Result<()> {
+    ctx.resume()
+        .map(|_promise| ())
+        .map_err(|err| anyhow!("Could not resume audio context {:#?}", err))
+}
+
 // 1. Create AudioBufferSource from AudioContext.
 // 2. Set AudioBuffer on the source.
-// 3. Connect AudioBufferSource to the AudioContext destination.
-// 4. Call start to play the sound.
-pub fn play_sound(ctx: &AudioContext, buffer: &AudioBuffer, looping: LOOPPING) -> Result<()> {
+// 3. Connect AudioBufferSource to its own GainNode, and the GainNode to the destination bus.
+// 4. Call start on the returned handle to play the sound.
+pub fn create_sound(
+    ctx: &AudioContext,
+    buffer: &AudioBuffer,
+    looping: LOOPPING,
+    destination: &AudioNode,
+) -> Result<SoundHandle> {
     let track_source = create_track_source(ctx, buffer)?;
     if matches!(looping, LOOPPING::YES) {
         track_source.set_loop(true)
     }
+    let gain = create_gain(ctx)?;
+    connect_with_audio_node(&track_source, &gain)?;
+    connect_with_audio_node(&gain, destination)?;
+
+    Ok(SoundHandle {
+        ctx: ctx.clone(),
+        source: track_source,
+        gain,
+        state: Rc::new(Cell::new(PlaybackState::Pending)),
+    })
+}
 
-    track_source
-        .start()
-        .map_err(|err| anyhow!("Could not start sound {:#?}", err))
+/// play_tone 用振荡器合成一个从 from_hz 滑到 to_hz 的音, 音量在 seconds 秒内淡出
+pub fn play_tone(
+    ctx: &AudioContext,
+    from_hz: f32,
+    to_hz: f32,
+    seconds: f64,
+    destination: &AudioNode,
+) -> Result<()> {
+    let oscillator = ctx
+        .create_oscillator()
+        .map_err(|err| anyhow!("Error creating oscillator {:#?}", err))?;
+    oscillator.set_type(OscillatorType::Triangle);
+    let gain = create_gain(ctx)?;
+    connect_with_audio_node(&oscillator, &gain)?;
+    connect_with_audio_node(&gain, destination)?;
+
+    let now = ctx.current_time();
+    oscillator
+        .frequency()
+        .set_value_at_time(from_hz, now)
+        .and_then(|frequency| frequency.exponential_ramp_to_value_at_time(to_hz, now + seconds))
+        .and_then(|_frequency| gain.gain().set_value_at_time(1.0, now))
+        .and_then(|gain| gain.linear_ramp_to_value_at_time(0.0, now + seconds))
+        .and_then(|_gain| oscillator.start_with_when(now))
+        .and_then(|_unit| oscillator.stop_with_when(now + seconds))
+        .map_err(|err| anyhow!("Could not play tone {:#?}", err))
 }
 
 pub async fn decode_audio_data(
@@ -47,18 +109,328 @@ fn create_buffer_source(ctx: &AudioContext) -> Result<AudioBufferSourceNode> {
         .map_err(|err| anyhow!("Error creating buffer source {:#?}", err))
 }
 
-fn connect_with_audio_node(
-    buffer_source: &AudioBufferSourceNode,
-    destination: &AudioDestinationNode,
-) -> Result<AudioNode> {
-    buffer_source
+fn create_gain(ctx: &AudioContext) -> Result<GainNode> {
+    ctx.create_gain()
+        .map_err(|err| anyhow!("Error creating gain node {:#?}", err))
+}
+
+fn connect_with_audio_node(source: &AudioNode, destination: &AudioNode) -> Result<AudioNode> {
+    source
         .connect_with_audio_node(destination)
-        .map_err(|err| anyhow!("Error creating audio source to destination {:#?}", err))
+        .map_err(|err| anyhow!("Error connecting audio source to destination {:#?}", err))
 }
 
 fn create_track_source(ctx: &AudioContext, buffer: &AudioBuffer) -> Result<AudioBufferSourceNode> {
     let track_source = create_buffer_source(ctx)?;
     track_source.set_buffer(Some(buffer));
-    connect_with_audio_node(&track_source, &ctx.destination())?;
     Ok(track_source)
 }
+
+/// Playback 是新的声音在 AudioContext 解锁之前或之后的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Playback {
+    Start,
+    Queue,
+    Drop,
+}
+
+/// PendingSounds 记录 AudioContext 有没有被用户的操作解锁.
+/// 解锁之前循环播放的声音排队, 等到 unlock 时一起开始; 一次性的音效过了时机就没有意义, 直接丢弃
+pub struct PendingSounds<T> {
+    unlocked: bool,
+    queued: Vec<T>,
+}
+
+impl<T> PendingSounds<T> {
+    pub fn new(unlocked: bool) -> Self {
+        PendingSounds {
+            unlocked,
+            queued: Vec::new(),
+        }
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.unlocked
+    }
+
+    pub fn playback(&self, looping: &LOOPPING) -> Playback {
+        match (self.unlocked, looping) {
+            (true, _) => Playback::Start,
+            (false, LOOPPING::YES) => Playback::Queue,
+            (false, LOOPPING::NO) => Playback::Drop,
+        }
+    }
+
+    pub fn queue(&mut self, sound: T) {
+        self.queued.push(sound);
+    }
+
+    /// unlock 之后所有的声音都直接播放, 返回之前排队的声音
+    pub fn unlock(&mut self) -> Vec<T> {
+        self.unlocked = true;
+        std::mem::take(&mut self.queued)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PlaybackState {
+    Pending,
+    Playing,
+    Stopped,
+}
+
+/// SoundHandle 是一个正在播放(或者排队等待播放)的声音, 可以用它停止声音或者调整音量.
+/// 还没开始就被停止的声音之后也不会再开始
+#[derive(Clone)]
+pub struct SoundHandle {
+    ctx: AudioContext,
+    source: AudioBufferSourceNode,
+    gain: GainNode,
+    state: Rc<Cell<PlaybackState>>,
+}
+
+impl SoundHandle {
+    pub fn start(&self) -> Result<()> {
+        if self.state.get() != PlaybackState::Pending {
+            return Ok(());
+        }
+        self.state.set(PlaybackState::Playing);
+        self.source
+            .start()
+            .map_err(|err| anyhow!("Could not start sound {:#?}", err))
+    }
+
+    pub fn stop(&self) -> Result<()> {
+        if self.state.replace(PlaybackState::Stopped) != PlaybackState::Playing {
+            return Ok(());
+        }
+        self.source
+            .stop()
+            .map_err(|err| anyhow!("Could not stop sound {:#?}", err))
+    }
+
+    pub fn set_volume(&self, volume: f32) {
+        self.gain.gain().set_value(volume);
+    }
+
+    pub fn set_playback_rate(&self, rate: f32) {
+        self.source.playback_rate().set_value(rate);
+    }
+
+    /// fade_to 在 seconds 秒内把音量线性地调整到 volume
+    pub fn fade_to(&self, volume: f32, seconds: f64) -> Result<()> {
+        let now = self.ctx.current_time();
+        let gain = self.gain.gain();
+        gain.cancel_scheduled_values(now)
+            .and_then(|gain| gain.set_value_at_time(gain.value(), now))
+            .and_then(|gain| gain.linear_ramp_to_value_at_time(volume, now + seconds))
+            .map(|_gain| ())
+            .map_err(|err| anyhow!("Could not fade sound {:#?}", err))
+    }
+
+    /// fade_out 淡出之后停止播放
+    pub fn fade_out(&self, seconds: f64) -> Result<()> {
+        if self.state.replace(PlaybackState::Stopped) != PlaybackState::Playing {
+            return Ok(());
+        }
+        self.fade_to(0.0, seconds)?;
+        self.source
+            .stop_with_when(self.ctx.current_time() + seconds)
+            .map_err(|err| anyhow!("Could not stop sound {:#?}", err))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Master,
+    Music,
+    Sfx,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 3] = [Channel::Master, Channel::Music, Channel::Sfx];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Channel::Master => "Master",
+            Channel::Music => "Music",
+            Channel::Sfx => "SFX",
+        }
+    }
+}
+
+/// Mixer 由三个 GainNode 组成: music 和 sfx 都接到 master 上, master 再接到扬声器
+pub struct Mixer {
+    master: GainNode,
+    music: GainNode,
+    sfx: GainNode,
+}
+
+impl Mixer {
+    pub fn new(ctx: &AudioContext) -> Result<Self> {
+        let master = create_gain(ctx)?;
+        let music = create_gain(ctx)?;
+        let sfx = create_gain(ctx)?;
+        connect_with_audio_node(&master, &ctx.destination())?;
+        connect_with_audio_node(&music, &master)?;
+        connect_with_audio_node(&sfx, &master)?;
+        Ok(Mixer { master, music, sfx })
+    }
+
+    pub fn bus(&self, channel: Channel) -> &GainNode {
+        match channel {
+            Channel::Master => &self.master,
+            Channel::Music => &self.music,
+            Channel::Sfx => &self.sfx,
+        }
+    }
+
+    pub fn apply(&self, settings: &VolumeSettings) {
+        Channel::ALL.iter().for_each(|channel| {
+            self.bus(*channel).gain().set_value(settings.gain(*channel));
+        });
+    }
+}
+
+fn full_volume() -> f32 {
+    1.0
+}
+
+/// VolumeSettings 是玩家设置的音量, 取值都在 0.0 到 1.0 之间, 会保存在 localStorage 里
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct VolumeSettings {
+    #[serde(default = "full_volume")]
+    pub master: f32,
+    #[serde(default = "full_volume")]
+    pub music: f32,
+    #[serde(default = "full_volume")]
+    pub sfx: f32,
+    #[serde(default)]
+    pub muted: bool,
+}
+
+impl Default for VolumeSettings {
+    fn default() -> Self {
+        VolumeSettings {
+            master: full_volume(),
+            music: full_volume(),
+            sfx: full_volume(),
+            muted: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedVolumeSettings {
+    version: u32,
+    #[serde(default)]
+    settings: VolumeSettings,
+}
+
+impl Versioned for SavedVolumeSettings {
+    const VERSION: u32 = VOLUME_VERSION;
+
+    fn version(&self) -> u32 {
+        self.version
+    }
+}
+
+impl VolumeSettings {
+    pub fn load(storage: &dyn Storage) -> Result<Self> {
+        let saved: Option<SavedVolumeSettings> = storage::load(storage, VOLUME_KEY)?;
+        Ok(saved.map(|saved| saved.settings).unwrap_or_default())
+    }
+
+    pub fn save(&self, storage: &dyn Storage) -> Result<()> {
+        storage::save(
+            storage,
+            VOLUME_KEY,
+            &SavedVolumeSettings {
+                version: VOLUME_VERSION,
+                settings: *self,
+            },
+        )
+    }
+
+    pub fn volume(&self, channel: Channel) -> f32 {
+        match channel {
+            Channel::Master => self.master,
+            Channel::Music => self.music,
+            Channel::Sfx => self.sfx,
+        }
+    }
+
+    pub fn set_volume(&mut self, channel: Channel, volume: f32) {
+        let volume = volume.clamp(0.0, 1.0);
+        match channel {
+            Channel::Master => self.master = volume,
+            Channel::Music => self.music = volume,
+            Channel::Sfx => self.sfx = volume,
+        }
+    }
+
+    /// gain 是实际设置到 GainNode 上的值, 静音只作用在 master 上, 取消静音后各个音量不变
+    pub fn gain(&self, channel: Channel) -> f32 {
+        match channel {
+            Channel::Master if self.muted => 0.0,
+            _ => self.volume(channel),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn mute_silences_master_and_keeps_channel_volumes() {
+        let mut settings = VolumeSettings::default();
+        settings.set_volume(Channel::Music, 1.5);
+        settings.set_volume(Channel::Sfx, 0.25);
+        settings.muted = true;
+
+        assert_eq!(settings.gain(Channel::Master), 0.0);
+        assert_eq!(settings.gain(Channel::Music), 1.0);
+        assert_eq!(settings.gain(Channel::Sfx), 0.25);
+    }
+
+    #[test]
+    fn pending_sounds_queue_music_and_drop_effects_until_unlocked() {
+        let mut pending = PendingSounds::new(false);
+
+        assert_eq!(pending.playback(&LOOPPING::NO), Playback::Drop);
+        assert_eq!(pending.playback(&LOOPPING::YES), Playback::Queue);
+        pending.queue("title");
+        pending.queue("running");
+
+        assert_eq!(pending.unlock(), vec!["title", "running"]);
+        assert!(pending.is_unlocked());
+        assert_eq!(pending.playback(&LOOPPING::NO), Playback::Start);
+        assert_eq!(pending.playback(&LOOPPING::YES), Playback::Start);
+        assert!(pending.unlock().is_empty());
+    }
+
+    #[test]
+    fn load_fills_missing_volumes_with_full_volume() {
+        let storage = MemoryStorage::new();
+        storage
+            .set(
+                VOLUME_KEY,
+                r#"{"version":1,"settings":{"music":0.5,"muted":true}}"#,
+            )
+            .unwrap();
+
+        let settings = VolumeSettings::load(&storage).unwrap();
+
+        assert_eq!(
+            settings,
+            VolumeSettings {
+                master: 1.0,
+                music: 0.5,
+                sfx: 1.0,
+                muted: true,
+            }
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::browser;
+
+/// Storage 是一个简单的键值存储, 浏览器里由 localStorage 实现, 原生测试里用 MemoryStorage 代替
+pub trait Storage {
+    fn get(&self, key: &str) -> Result<Option<String>>;
+    fn set(&self, key: &str, value: &str) -> Result<()>;
+}
+
+pub struct LocalStorage {
+    storage: web_sys::Storage,
+}
+
+impl LocalStorage {
+    pub fn new() -> Result<Self> {
+        Ok(LocalStorage {
+            storage: browser::local_storage()?,
+        })
+    }
+}
+
+impl Storage for LocalStorage {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.storage
+            .get_item(key)
+            .map_err(|err| anyhow!("Could not read {} from localStorage {:#?}", key, err))
+    }
+
+    fn set(&self, key: &str, value: &str) -> Result<()> {
+        self.storage
+            .set_item(key, value)
+            .map_err(|err| anyhow!("Could not write {} to localStorage {:#?}", key, err))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    values: RefCell<HashMap<String, String>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        Ok(self.values.borrow().get(key).cloned())
+    }
+
+    fn set(&self, key: &str, value: &str) -> Result<()> {
+        self.values.borrow_mut().insert(key.into(), value.into());
+        Ok(())
+    }
+}
+
+/// Versioned 是带版本号的存档格式. 比 VERSION 新的存档是新版本的游戏写的,
+/// 读出来会丢掉不认识的字段, 所以既不读取也不覆盖
+pub trait Versioned {
+    const VERSION: u32;
+
+    fn version(&self) -> u32;
+}
+
+pub fn load<T: DeserializeOwned + Versioned>(
+    storage: &dyn Storage,
+    key: &str,
+) -> Result<Option<T>> {
+    storage
+        .get(key)?
+        .map(|value| parse(key, &value))
+        .transpose()
+}
+
+/// save 不会覆盖读不出来的存档(格式错误或者版本太新), 免得读取失败之后用默认值把玩家的数据冲掉
+pub fn save<T: Serialize + DeserializeOwned + Versioned>(
+    storage: &dyn Storage,
+    key: &str,
+    value: &T,
+) -> Result<()> {
+    if let Some(existing) = storage.get(key)? {
+        parse::<T>(key, &existing)
+            .map_err(|err| anyhow!("Not overwriting a save that could not be read: {}", err))?;
+    }
+    let value = serde_json::to_string(value)
+        .map_err(|err| anyhow!("Could not serialize {}: {}", key, err))?;
+    storage.set(key, &value)
+}
+
+fn parse<T: DeserializeOwned + Versioned>(key: &str, value: &str) -> Result<T> {
+    let saved: T =
+        serde_json::from_str(value).map_err(|err| anyhow!("Could not parse {}: {}", key, err))?;
+    if saved.version() > T::VERSION {
+        return Err(anyhow!(
+            "{} was saved by a newer version ({} > {})",
+            key,
+            saved.version(),
+            T::VERSION
+        ));
+    }
+    Ok(saved)
+}